@@ -4,21 +4,255 @@
 // It will then calculate the result of said instructions and return the value
 
 extern crate byteorder;
+#[cfg(feature = "jit")]
+extern crate cranelift_codegen;
+#[cfg(feature = "jit")]
+extern crate cranelift_frontend;
+#[cfg(feature = "jit")]
+extern crate cranelift_jit;
+#[cfg(feature = "jit")]
+extern crate cranelift_module;
+#[cfg(feature = "jit")]
+extern crate cranelift_native;
+
+mod cfg;
+mod cgen;
+mod coverage;
+mod debug;
+mod debuginfo;
+mod isa;
+mod link;
+mod liveness;
+mod object;
+mod opt;
+mod profile;
+mod snapshot;
+mod stack;
+mod word;
+#[cfg(feature = "jit")]
+mod jit;
 
 use self::byteorder::{ByteOrder,BigEndian,ReadBytesExt};
 use std::slice::Iter;
 use std::fs;
 use std::env;
-use std::convert::TryInto;
+use std::fmt;
+use std::rc::Rc;
+use std::io::{self,BufRead,Write};
+
+use word::Word;
+pub use isa::{Instr, Unop, Binop};
 
 #[derive(Debug,Clone)]
 pub struct State {
     pub halt: bool, //Has the machine halted?
     pub pc: u32, //The current program counter, a 32-bit unsigned integer
     pub fp: u32, //The current frame pointer
-    pub stack: Vec<Val>, //The stack, with maximum size STACK_SIZE
-    pub heap: Vec<Val>, //The heap
-    pub program: Vec<Instr> //The program being executed, a list of instructions
+    pub stack: Vec<Word>, //The stack, with maximum size STACK_SIZE
+    pub heap: Vec<Word>, //The heap
+    pub control: Vec<Frame>, //The control stack: saved frame pointers and return addresses, innermost last
+    pub input: Input, //Where ReadChar and ReadInt take their bytes from
+    pub handlers: Vec<Handler>, //The exception handlers installed by PushHandler, innermost last
+    pub natives: Vec<Native> //The host functions CallNative can reach, in registration order
+}
+
+pub const RETURN_TO_HOST: u32 = u32::MAX; //The return address State::call gives the function it calls
+
+#[derive(Debug,Clone)]
+pub enum Entry<'a> {
+    Loc(u32),        //Call the function starting at this instruction
+    Symbol(&'a str)  //Call the function the program exports under this name
+}
+
+#[derive(Debug,Clone)]
+pub struct Program {
+    pub code: Vec<Instr>,           //The instructions
+    pub imports: Vec<String>,       //Native function names, CallNative(i) refers to imports[i]
+    pub exports: Vec<(String, u32)>, //Entry point names and their instruction locations
+    pub constants: Vec<Vec<Val>>,   //The constant objects PushConst(i) refers to, the fields of each
+    pub debug: Option<debuginfo::DebugInfo>, //Function names, source lines and local names, if the object file has them
+    pub heap: Vec<Val>,             //What the heap holds before the program starts
+    pub relocations: Option<link::Relocations> //Where the code addresses and external symbols are, if the object is relocatable
+}
+
+pub type NativeFn = Rc<dyn Fn(&mut State, &[Val]) -> Result<Val, VmError>>;
+
+#[derive(Clone)]
+pub struct Native {
+    pub name: String, //The name programs import the function by
+    pub arity: usize, //How many values it pops off the stack
+    pub f: NativeFn   //The host function itself
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({}/{})", self.name, self.arity)
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    pub fn new() -> State { // function to make a fresh machine, with no natives registered yet
+        State{halt: false, pc: 0, fp: 0, stack: Vec::new(), heap: Vec::new(), control: Vec::new(),
+              input: Input::Stdin, handlers: Vec::new(), natives: Vec::new()}
+    }
+
+    pub fn call(&mut self, program: &Program, entry: Entry, args: &[Val]) -> Result<Val, VmError> { // function to run a bytecode function of program from the host and hand back what it returns
+        let target = match entry {
+            Entry::Loc(l) => l,
+            Entry::Symbol(name) => {
+                match program.exports.iter().find(|e| e.0 == name){
+                    Some(e) => e.1,
+                    None => return Err(VmError::UnknownSymbol(name.to_string()))
+                }
+            }
+        };
+        let caller_pc = self.pc;
+        let caller_fp = self.fp;
+        let base = self.stack.len();
+        let control_base = self.control.len();
+        let outer_handlers = std::mem::take(&mut self.handlers); // only handlers installed by the callee may catch its errors
+        self.halt = false;
+
+        for a in args { // lay out the frame the way Push(args); Push(f); SetFrame(n); Swap; Call would
+            if self.stack.len() > 1024 {
+                self.stack.truncate(base);
+                self.handlers = outer_handlers;
+                return Err(VmError::StackOverflow);
+            }
+            self.stack.push(Word::from(a));
+        }
+        self.stack.push(Word::loc(self.fp));
+        self.fp = (self.stack.len() - args.len() - 1) as u32;
+        self.stack.push(Word::loc(RETURN_TO_HOST));
+        self.control.push(Frame{fp: caller_fp, ret: Some(RETURN_TO_HOST)});
+        self.pc = target;
+
+        let mut result = Ok(());
+        'callloop: loop{ // run until the callee's Ret sends us back to the host
+            if self.pc == RETURN_TO_HOST { break 'callloop }
+            if self.halt {
+                result = Err(VmError::Halted);
+                break 'callloop;
+            }
+            result = fetch(&program.code, self).and_then(|i| step(i, self));
+            if result.is_err() { break 'callloop }
+        }
+
+        let ret = match result {
+            Ok(()) => self.stack.pop().map(Word::val).ok_or(VmError::StackUnderflow("No return value -- call")),
+            Err(e) => Err(e)
+        };
+        self.stack.truncate(base); // leave the machine as we found it so it can be called again
+        self.control.truncate(control_base);
+        self.pc = caller_pc;
+        self.fp = caller_fp;
+        self.halt = false;
+        self.handlers = outer_handlers;
+        ret
+    }
+
+    pub fn register_native<F>(&mut self, name: &str, arity: usize, f: F) -> u32
+        where F: Fn(&mut State, &[Val]) -> Result<Val, VmError> + 'static { // function to add a host function, returning its CallNative index
+        self.natives.push(Native{name: name.to_string(), arity, f: Rc::new(f)});
+        (self.natives.len() - 1) as u32
+    }
+}
+
+impl Program {
+    pub fn link_natives(&mut self, natives: &[Native]) -> Result<(), String> { // function to rewrite CallNative(i) from the import table index i to its index in natives
+        if self.imports.is_empty() { // without an import table CallNative(i) is the registration index itself
+            return Ok(());
+        }
+        let mut resolved = Vec::new();
+        for name in self.imports.iter() {
+            match natives.iter().position(|n| &n.name == name){
+                Some(idx) => resolved.push(idx as u32),
+                None => return Err(format!("Unknown native function {}", name))
+            }
+        }
+        for i in self.code.iter_mut(){
+            if let Instr::CallNative(x) = i {
+                match resolved.get(*x as usize){
+                    Some(idx) => *x = *idx,
+                    None => return Err(format!("CallNative({}) has no entry in the import table", x))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_data(&mut self) -> Result<Vec<Word>, String> { // function to lay out the heap the program starts with: the heap section, then each constant object behind its Vsize header, pointing every PushConst at its object
+        let mut heap: Vec<Word> = self.heap.iter().map(Word::from).collect();
+        let mut addresses = Vec::new();
+        for object in self.constants.iter() {
+            addresses.push(heap.len());
+            heap.push(Word::size(object.len() as i32));
+            heap.extend(object.iter().map(Word::from));
+        }
+        if heap.len() > 1025 { // what Alloc lets the heap grow to
+            return Err(format!("The program's data takes {} heap values, more than the heap holds", heap.len()));
+        }
+        for i in self.code.iter_mut(){
+            if let Instr::PushConst(x) = *i {
+                match addresses.get(x as usize){
+                    Some(a) => *i = Instr::Push(Val::Vaddr(*a)),
+                    None => return Err(format!("PushConst({}) has no constant object", x))
+                }
+            }
+        }
+        Ok(heap)
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Frame {
+    pub fp: u32,         //The caller's frame pointer, restored by Ret
+    pub ret: Option<u32> //Where Ret goes back to, None between a SetFrame and its Call
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Handler {
+    pub target: u32,  //The instruction location to resume at when a value is thrown
+    pub fp: u32,      //The frame pointer to restore
+    pub sp: usize,    //The stack height to unwind to before pushing the thrown value
+    pub frames: usize //The control stack height to unwind to
+}
+
+#[derive(Debug,Clone)]
+pub enum Input {
+    Stdin,                 //Read from the process's standard input (the default)
+    Buffer(Vec<u8>, usize) //Read from an in-memory byte buffer, starting at the given position
+}
+
+impl Input {
+    fn peek_byte(&mut self) -> Option<u8> { // look at the next input byte without consuming it
+        match self {
+            Input::Stdin => {
+                let stdin = io::stdin();
+                let mut handle = stdin.lock();
+                let buf = handle.fill_buf().expect("Could not read from stdin");
+                buf.first().cloned()
+            }
+            Input::Buffer(bytes, pos) => bytes.get(*pos).cloned()
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> { // consume and return the next input byte, None at end of input
+        let b = self.peek_byte();
+        if b.is_some() {
+            match self {
+                Input::Stdin => io::stdin().lock().consume(1),
+                Input::Buffer(_, pos) => *pos += 1
+            }
+        }
+        b
+    }
 }
 
 pub trait fromBinary {
@@ -50,43 +284,6 @@ impl fromBinary for u32 { // function to convert binary into U32
 }
 
 
-#[derive(Debug,Clone)]
-pub enum Unop {
-    Neg, //Boolean negation
-}
-
-impl fromBinary for Unop { // function to convert binary into Unop
-    fn from_binary(bytes: &mut Iter<u8>) -> Self {
-        match bytes.next().unwrap(){
-            0b0000_0000 => Unop::Neg,
-            _=> panic!("Bad!")
-        }
-    }
-}
-
-#[derive(Debug,Clone)]
-pub enum Binop {
-    Add, //i32 addition
-    Mul, //i32 multiplication
-    Sub, //i32 subtraction
-    Div, //i32 division (raises an error on divide by zero)
-    Lt,  //Returns true if one i32 is less than another, otherwise false
-    Eq,  //Returns true if one i32 is equal another, otherwise false
-}
-
-impl fromBinary for Binop { // function to convert binary into Binops
-    fn from_binary(bytes: &mut Iter<u8>) -> Self {
-        match bytes.next().unwrap(){
-            0b0000_0000 => Binop::Add,
-            0b0000_0001 => Binop::Mul,
-            0b0000_0010 => Binop::Sub,
-            0b0000_0011 => Binop::Div,
-            0b0000_0100 => Binop::Lt,
-            0b0000_0101 => Binop::Eq,
-            _=> panic!("Bad!")
-        }
-    }
-}
 type Address = usize; // used with Vaddr inside Val enum
 #[derive(Debug,Clone,PartialEq)]
 pub enum Val {
@@ -99,452 +296,680 @@ pub enum Val {
     Vaddr(Address) //Pointers to heap locations
 }
 
-impl fromBinary for Val { // function to convert binary into Vals
-    fn from_binary(bytes: &mut Iter<u8>) -> Self {
-        match bytes.next().unwrap(){
-            0b0000_0000 => Val::Vunit,
-            0b0000_0001 => {
-                let i = <i32 as fromBinary>::from_binary(bytes);
-                return Val::Vi32(i);
-            }
-            0b0000_0100 => {
-                let i = <u32 as fromBinary>::from_binary(bytes);
-                return Val::Vloc(i);
-            }
-            0b0000_0010 => Val::Vbool(true),
-            0b0000_0011 => Val::Vbool(false),
-            0b0000_0101 => Val::Vundef,
-            _=> panic!("Bad!")
+#[derive(Debug,Clone,PartialEq)]
+pub enum VmError {
+    StackOverflow,                //Pushed past the 1024 value stack limit
+    StackUnderflow(&'static str), //Popped from an empty stack
+    TypeError(&'static str),      //An operand had the wrong kind of value
+    DivideByZero,                 //Binary Div with a zero divisor
+    BadHeapIndex(i32),            //Set or Get outside of the heap
+    BadStackIndex(&'static str),  //Var, Store or Peek outside of the stack
+    HeapOverflow,                 //Alloc past the 1024 value heap limit
+    BadInput(&'static str),       //ReadInt found something that is not an i32
+    NoHandler,                    //PopHandler with no handler installed
+    Thrown(Val),                  //A value thrown by Throw
+    UnknownSymbol(String),        //A host call named an entry point the program doesn't export
+    Halted,                       //The program halted before a host call returned
+    BadPc(u32),                   //Jumped outside of the program
+    Overflow(&'static str)        //Binary Div of i32::MIN by -1
+}
+
+impl VmError {
+    pub fn to_val(&self) -> Val { // the value a handler receives for this error, VM errors are thrown as their i32 error code
+        match self {
+            VmError::StackOverflow => Val::Vi32(1),
+            VmError::StackUnderflow(_) => Val::Vi32(2),
+            VmError::TypeError(_) => Val::Vi32(3),
+            VmError::DivideByZero => Val::Vi32(4),
+            VmError::BadHeapIndex(_) => Val::Vi32(5),
+            VmError::BadStackIndex(_) => Val::Vi32(6),
+            VmError::HeapOverflow => Val::Vi32(7),
+            VmError::BadInput(_) => Val::Vi32(8),
+            VmError::NoHandler => Val::Vi32(9),
+            VmError::UnknownSymbol(_) => Val::Vi32(10),
+            VmError::Halted => Val::Vi32(11),
+            VmError::BadPc(_) => Val::Vi32(12),
+            VmError::Overflow(_) => Val::Vi32(13),
+            VmError::Thrown(v) => v.clone()
         }
     }
 }
 
-#[derive(Debug,Clone)]
-pub enum Instr {
-    Push(Val),     //Push(v): Push value v onto the stack      // Push Label onto the stack
-    Pop,           //Pop a value from the stack, discarding it
-    Peek(u32),     //Peek(i): Push onto the stack the ith value from the top
-    Unary(Unop),   //Unary(u): Apply u to the top value on the stack
-    Binary(Binop), //Binary(b): Apply b to the top two values on the stack, replacing them with the result
-    Swap,          //Swap the top two values
-    Alloc,         //Allocate an array on the heap
-    Set,           //Write to a heap-allocated array
-    Get,           //Read from a heap-allocated array
-    Var(u32),      //Var(i): Get the value at stack position fp+i
-    Store(u32),    //Store(i): Store a value at stack position fp+i
-    SetFrame(u32), //SetFrame(i): Set fp = s.stack.len() - i
-    Call,          //Function call
-    Ret,           //Function return
-    Branch,        //Conditional jump
-    Halt           //Halt the machine
- }
-
- impl fromBinary for Instr { // function to convert binary  into instructions 
-    fn from_binary(bytes: &mut Iter<u8>) -> Self {
-        match bytes.next().unwrap(){
-            0b0000_0000 => Instr::Push(Val::from_binary(bytes)),
-            0b0000_0001 => Instr::Pop, 
-            0b0000_0010 => {
-                let i = <u32 as fromBinary>::from_binary(bytes);
-                return Instr::Peek(i);
-            } 
-            0b0000_0011 => Instr::Unary(Unop::from_binary(bytes)),
-            0b0000_0100 => Instr::Binary(Binop::from_binary(bytes)),
-            0b0000_0101 => Instr::Swap, 
-            0b0000_0110 => Instr::Alloc,
-            0b0000_0111 => Instr::Set,
-            0b0000_1000 => Instr::Get,
-            0b0000_1001 => {
-                let i = <u32 as fromBinary>::from_binary(bytes);
-                return Instr::Var(i);
-            }  
-            0b0000_1010 => {
-                let i = <u32 as fromBinary>::from_binary(bytes);
-                return Instr::Store(i);
-            }  
-            0b0000_1011 => {
-                let i = <u32 as fromBinary>::from_binary(bytes);
-                return Instr::SetFrame(i);
-            } 
-            0b0000_1100 => Instr::Call,
-            0b0000_1101 => Instr::Ret,
-            0b0000_1110 => Instr::Branch,
-            0b0000_1111 => Instr::Halt,
-            _=> panic!("Bad! No match for the binary given")
-        }
-    }
-}
-
-fn eval_unary(s: &mut State){ // function to negate a bool value at top of stack
-    let stack_top = s.stack.pop().expect("No value to pop"); // grabs top stack value
-    match stack_top{ // match statement to ensure top value is a vbool
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackOverflow => write!(f, "Stack size exceeded"),
+            VmError::StackUnderflow(msg) => write!(f, "{}", msg),
+            VmError::TypeError(msg) => write!(f, "{}", msg),
+            VmError::DivideByZero => write!(f, "Cannot divide by zero -- Binary Div"),
+            VmError::BadHeapIndex(i) => write!(f, "Heap index {} out of bounds", i),
+            VmError::BadStackIndex(msg) => write!(f, "{}", msg),
+            VmError::HeapOverflow => write!(f, "Heap is out of bounds"),
+            VmError::BadInput(msg) => write!(f, "{}", msg),
+            VmError::NoHandler => write!(f, "No handler to pop -- PopHandler"),
+            VmError::Thrown(v) => write!(f, "Uncaught exception: {:?}", v),
+            VmError::UnknownSymbol(name) => write!(f, "No entry point named {}", name),
+            VmError::Halted => write!(f, "Program halted before returning to the host"),
+            VmError::BadPc(pc) => write!(f, "pc {} out of bounds", pc),
+            VmError::Overflow(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+fn eval_unary(s: &mut State) -> Result<(), VmError> { // function to negate a bool value at top of stack
+    let stack_top = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop"))?; // grabs top stack value
+    match stack_top.val(){ // match statement to ensure top value is a vbool
         Val::Vbool(y) =>{
             match y{
                 true => { // if value is true, negate it to false
                     let new_bool = false;
-                    s.stack.push(Val::Vbool(new_bool));
+                    s.stack.push(Word::bool(new_bool));
                 }
                 false =>{ // if value is false, negate it to true 
                     let new_bool = true;
-                    s.stack.push(Val::Vbool(new_bool));
+                    s.stack.push(Word::bool(new_bool));
                 }
-                _=> panic!("Invalid bool made it through") // if somehow a value other than T/F slips through
+                _=> return Err(VmError::TypeError("Invalid bool made it through")) // if somehow a value other than T/F slips through
             }
         }
-        _=> panic!("cant apply unary to non-bool") // panics if not a bool value
+        _=> return Err(VmError::TypeError("cant apply unary to non-bool")) // raises an error if not a bool value
     }
+    Ok(())
 }
 
-fn eval_binary(b: Binop, s: &mut State){ // function that applies binary operator b to two Val's
-        let e1 = s.stack.pop().expect("No top value to pop -- Binary");
-        let e2 = s.stack.pop().expect("No secondary value to pop -- Binary");
+fn eval_binary(b: &Binop, s: &mut State) -> Result<(), VmError> { // function that applies binary operator b to two Val's
+        let e1 = s.stack.pop().ok_or(VmError::StackUnderflow("No top value to pop -- Binary"))?;
+        let e2 = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop -- Binary"))?;
 
         match b{
             Binop::Add => { // addition case for binary operator
-                match e1 {
-                    Val::Vi32(v1) =>{
-                        match e2 {
-                            Val::Vi32(v2) => {
-                                s.stack.push(Val::Vi32(v1 + v2));
+                match e1.as_i32() {
+                    Some(v1) =>{
+                        match e2.as_i32() {
+                            Some(v2) => {
+                                s.stack.push(Word::i32(v1.wrapping_add(v2))); // wraps, like the JIT and the C backend
                             }
-                            _=> panic!("Expected Vi32 -- Binary add")
+                            None => return Err(VmError::TypeError("Expected Vi32 -- Binary add"))
                         }
                     }
-                    _ => panic!("Expected Vi32 -- Binary add")
+                    None => return Err(VmError::TypeError("Expected Vi32 -- Binary add"))
                 }
             }
             Binop::Sub =>{ // subtraction case for binary operator
-                match e1 {
-                    Val::Vi32(v1) =>{
-                        match e2 {
-                            Val::Vi32(v2) =>  {
-                                s.stack.push(Val::Vi32(v1 - v2));
+                match e1.as_i32() {
+                    Some(v1) =>{
+                        match e2.as_i32() {
+                            Some(v2) =>  {
+                                s.stack.push(Word::i32(v1.wrapping_sub(v2)));
                             }
-                            _=> panic!("Expected Vi32 -- Binary sub")
+                            None => return Err(VmError::TypeError("Expected Vi32 -- Binary sub"))
                         }
                     }
-                    _ => panic!("Expected Vi32 -- Binary sub")
+                    None => return Err(VmError::TypeError("Expected Vi32 -- Binary sub"))
                 }
             }
             Binop::Mul =>{ // multiplication case for binary operator
-                match e1 {
-                    Val::Vi32(v1) =>{
-                        match e2 {
-                            Val::Vi32(v2) => {
-                                s.stack.push(Val::Vi32(v1 * v2));
+                match e1.as_i32() {
+                    Some(v1) =>{
+                        match e2.as_i32() {
+                            Some(v2) => {
+                                s.stack.push(Word::i32(v1.wrapping_mul(v2)));
                             }
-                            _=> panic!("Expected Vi32 -- Binary mul")
+                            None => return Err(VmError::TypeError("Expected Vi32 -- Binary mul"))
                         }
                     }
-                    _ => panic!("Expected Vi32 -- Binary mul")
+                    None => return Err(VmError::TypeError("Expected Vi32 -- Binary mul"))
                 }
             }
             Binop::Div =>{ // division case for binary operator
-                match e1 {
-                    Val::Vi32(v1) =>{
-                        match e2 {
-                            Val::Vi32(v2) => {
-                                if v2 != 0{
-                                    s.stack.push(Val::Vi32(v1 / v2));
+                match e1.as_i32() {
+                    Some(v1) =>{
+                        match e2.as_i32() {
+                            Some(v2) => {
+                                if v2 == 0 {
+                                    return Err(VmError::DivideByZero);
                                 }
-                                else {
-                                    panic!("Cannot divide by zero -- Binary Div");
+                                match v1.checked_div(v2) {
+                                    Some(q) => s.stack.push(Word::i32(q)),
+                                    None => return Err(VmError::Overflow("Division overflows an i32 -- Binary Div"))
                                 }
                             }
-                            _=> panic!("Expected Vi32 -- Binary div")
+                            None => return Err(VmError::TypeError("Expected Vi32 -- Binary div"))
                         }
                     }
-                    _ => panic!("Expected Vi32 -- Binary div")
+                    None => return Err(VmError::TypeError("Expected Vi32 -- Binary div"))
                 }
             }
             Binop::Lt => // less than case for binary operator
             {
-                match e1 {
-                    Val::Vi32(v1) =>{
-                        match e2 {
-                            Val::Vi32(v2) => {
+                match e1.as_i32() {
+                    Some(v1) =>{
+                        match e2.as_i32() {
+                            Some(v2) => {
                                    if v1 < v2 {
-                                        s.stack.push(Val::Vbool(true));
+                                        s.stack.push(Word::bool(true));
                                    }
                                    else{
-                                        s.stack.push(Val::Vbool(false));
+                                        s.stack.push(Word::bool(false));
                                    }
                             }
-                            _=> panic!("Expected Vi32 -- Binary lt")
+                            None => return Err(VmError::TypeError("Expected Vi32 -- Binary lt"))
                         }
                     }
-                    _ => panic!("Expected Vi32 -- Binary lt")
+                    None => return Err(VmError::TypeError("Expected Vi32 -- Binary lt"))
                 }
             }
-            Binop::Eq => { // equal to case for binary operator
-                match e1 {
-                    Val::Vi32(v1) =>{ 
-                        match e2 {
-                            Val::Vi32(v2) => {
-                                   if v1 == v2 {
-                                        s.stack.push(Val::Vbool(true));
-                                   }
-                                   else{
-                                        s.stack.push(Val::Vbool(false));
-                                   }
-                            }
-                            _=> panic!("Expected Vi32 -- Binary Eq")
-                        }
-                    }
-                    _ => panic!("Expected Vi32 -- Binary Eq")
+            Binop::Eq => { // equal to case for binary operator, which can also test for the Vunit ReadChar and ReadInt push at end of input
+                match (e1.as_i32(), e2.as_i32()) {
+                    (Some(v1), Some(v2)) => s.stack.push(Word::bool(v1 == v2)),
+                    _ if e1 == Word::UNIT || e2 == Word::UNIT => s.stack.push(Word::bool(e1 == e2)),
+                    _ => return Err(VmError::TypeError("Expected Vi32 or Vunit -- Binary eq"))
                 }
             }
         }
+    Ok(())
 }
 
-fn eval_alloc(s: &mut State){ // function to allocate values onto stack
-    let top_of_stack = s.stack.pop().expect("No value to pop"); 
-    let second_top_value = s.stack.pop().expect("No secondary value to pop");
+fn eval_alloc(s: &mut State) -> Result<(), VmError> { // function to allocate values onto stack
+    let top_of_stack = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop"))?; 
+    let second_top_value = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop"))?;
 
-    match second_top_value{
+    match second_top_value.val(){
         Val::Vi32(x) => { // if the value is an i32, continue
-                s.heap.push(Val::Vsize(x)); // push metadata for array size
+                s.heap.push(Word::size(x)); // push metadata for array size
                 let mut counter = 0;
                 'heaploop: loop{ // pushes top of stack value onto heap until our counter is equal to second_top_val
                     if counter == x{break 'heaploop};
                     if s.heap.len() > 1024 {
-                        panic!("Heap is out of bounds")
+                        return Err(VmError::HeapOverflow)
                     }
-                    s.heap.push(top_of_stack.clone());
+                    s.heap.push(top_of_stack);
                     counter = counter + 1;
                 }
                 let x_as_usize = x as usize ; // convert oto u32 for ensuing subtraction
                 let array_start = s.heap.len() - x_as_usize - 1; 
-                s.stack.push(Val::Vaddr(array_start)); // push vaddr onto stack
+                s.stack.push(Word::addr(array_start)); // push vaddr onto stack
         }
-        _=> panic!("Expected Vi32") // anything else will cause a panic
+        _=> return Err(VmError::TypeError("Expected Vi32")) // anything else will raise an error
     }
+    Ok(())
 }
 
-fn eval_set (s: &mut State){ // function to store value at heap address base + idx + 1
-    let val_to_be_stored = s.stack.pop().expect("Nothing to be popped in Set function"); // val to be stored
-    let idx = s.stack.pop().expect("Can't pop a second value in Set function"); // idx
-    let addr = s.stack.pop().expect("Can't pop a third value from Set function"); // base 
+fn eval_set (s: &mut State) -> Result<(), VmError> { // function to store value at heap address base + idx + 1
+    let val_to_be_stored = s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to be popped in Set function"))?; // val to be stored
+    let idx = s.stack.pop().ok_or(VmError::StackUnderflow("Can't pop a second value in Set function"))?; // idx
+    let addr = s.stack.pop().ok_or(VmError::StackUnderflow("Can't pop a third value from Set function"))?; // base 
 
-     match idx{
-             Val::Vi32(x) => { // idx must be i32
+     match idx.as_i32(){
+             Some(x) => { // idx must be i32
                      let idx_val = x;
-                     match addr {
-                         Val::Vaddr(y) => { // addr must be type Vaddr
-                             let y_as_u32 = y as i32;
-                             let index_in_heap = (idx_val + y_as_u32 + 1) as usize;
-                             s.heap[index_in_heap] = val_to_be_stored; // store in heap at given index
+                     match addr.as_addr() {
+                         Some(y) => { // addr must be type Vaddr
+                             let index_in_heap = idx_val as i64 + y as i64 + 1; // in i64 so a large idx can't wrap back into the heap
+                             if idx_val < 0 || index_in_heap >= s.heap.len() as i64 {
+                                 return Err(VmError::BadHeapIndex(index_in_heap.min(i32::MAX as i64) as i32))
+                             }
+                             s.heap[index_in_heap as usize] = val_to_be_stored; // store in heap at given index
                          }
-                         _ => panic!("Invalid address location inside Set function") 
+                         None => return Err(VmError::TypeError("Invalid address location inside Set function")) 
                      }
              }
-             _ => panic!("Can't index a non i32 in Set function")
+             None => return Err(VmError::TypeError("Can't index a non i32 in Set function"))
      }
+    Ok(())
 }
 
-fn eval_get(s: &mut State){ // push value contained at heap address base + idx + 1 onto the stack
-    let top_of_stack = s.stack.pop().expect("Nothing to pop -- Get function"); // idx
-    let secondary_top = s.stack.pop().expect("No secondary value to pop -- Get function"); // base
+fn eval_get(s: &mut State) -> Result<(), VmError> { // push value contained at heap address base + idx + 1 onto the stack
+    let top_of_stack = s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to pop -- Get function"))?; // idx
+    let secondary_top = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop -- Get function"))?; // base
     
-    match top_of_stack{
-        Val::Vi32(x) =>{ // must be i32
-                let idx = x as u32;
-                match secondary_top{
-                    Val::Vaddr(y) => { // must be vaddr
-                        let base = y as u32;
-                        let heap_loc = (idx + base + 1) as usize; // index on the heap
-                        let stack_val = s.heap[heap_loc].clone(); //value to be stored
+    match top_of_stack.as_i32(){
+        Some(x) =>{ // must be i32
+                let idx = x;
+                match secondary_top.as_addr(){
+                    Some(y) => { // must be vaddr
+                        let heap_loc = idx as i64 + y as i64 + 1; // index on the heap, in i64 so a large idx can't wrap back into it
+                        if idx < 0 || heap_loc >= s.heap.len() as i64 {
+                            return Err(VmError::BadHeapIndex(heap_loc.min(i32::MAX as i64) as i32))
+                        }
+                        let heap_loc = heap_loc as usize;
+                        let stack_val = s.heap[heap_loc]; //value to be stored
                         s.stack.push(stack_val);  // push onto stack
                     }
-                    _=> panic!("Get requires a Vaddr as secondary stack location")
+                    None => return Err(VmError::TypeError("Get requires a Vaddr as secondary stack location"))
                 }
         }
-        _=> panic!("Get requires an i32 at top of stack")
+        None => return Err(VmError::TypeError("Get requires an i32 at top of stack"))
 
     }
+    Ok(())
 }
 
-fn eval_ret(s: &mut State){ // function to grab return value, restore caller_fp and caller_pc, and remove unecessary variables
-    let mut ret_val = Val::Vi32(0); // had to initalize to something so I could use in if statements
-    let mut caller_pc_vloc = Val::Vloc(0); // had to initalize to something so I could use in if statements
-    let mut caller_fp_vloc = Val::Vloc(0); //had to initalize to something so I could use in if statements
-    let mut end = s.fp; // initalize a counter to the frame pointer 
-    let mut counter = (s.stack.len() - 1) as u32; // counter to count down from stack.len() -1 to framepointer
-    let stack_size = s.stack.len() ; // variable that keeps track of initial size of stack before pops
-
-    'poploop: loop{ // loop that goes from counter to fp and decrements counter each iteration
-        if counter == (stack_size - 3) as u32 {
-            caller_fp_vloc = s.stack.pop().expect("No tertiary value to pop -- Ret function");
-            if (s.stack.len() == 0){
-                break 'poploop;
+fn object_len(s: &State, addr: Word, who: &'static str) -> Result<(usize, i32), VmError> { // function to find the base and Vsize header of the heap object at addr
+    match addr.val(){
+        Val::Vaddr(base) => {
+            match s.heap.get(base).map(|w| w.val()){
+                Some(Val::Vsize(n)) => Ok((base, n)),
+                Some(_) => Err(VmError::TypeError(who)), // the address doesn't point at an object header
+                None => Err(VmError::BadHeapIndex(base as i32))
             }
-            counter = counter - 1;
-        }
-        else if counter == (stack_size - 2) as u32 {
-             caller_pc_vloc = s.stack.pop().expect("No secondary value to pop -- Ret function");
-             counter = counter - 1;
-        }
-        else if  counter == (stack_size - 1) as u32 {
-             ret_val = s.stack.pop().expect("No value to pop -- Ret function");
-             counter = counter - 1;
         }
-        else {
-            if s.stack.len() != 0{
-                s.stack.pop().expect("No value to pop -- Ret function");
-            }
-            if counter == end || counter < 0 {
-                break 'poploop ;
-            }
-            counter = counter - 1;
-        }
-    }
-    match caller_pc_vloc{ 
-        Val::Vloc(x) =>{
-            let caller_pc = x ;
-            match caller_fp_vloc{
-                Val::Vloc(y) => {
-                        let caller_fp = y;
-                        s.fp = caller_fp;
-                        s.pc = caller_pc;
-                        s.stack.push(ret_val);
-                }
-                _=> panic!("caller_fp must be vloc")
+        _=> Err(VmError::TypeError(who))
+    }
+}
+
+fn check_range(s: &State, base: usize, len: i32, start: Word, count: Word, who: &'static str) -> Result<(usize, usize), VmError> { // function to make sure start..start+count lies inside the object of length len at base, giving the heap index the range starts at and its length
+    match (start.val(), count.val()){
+        (Val::Vi32(i), Val::Vi32(n)) => {
+            let end = i as i64 + n as i64; // i64 so a start near i32::MAX can't wrap around
+            if i < 0 || n < 0 || end > len as i64 || base as i64 + 1 + end > s.heap.len() as i64 {
+                return Err(VmError::BadHeapIndex(i.saturating_add(n)))
             }
+            Ok((base + 1 + i as usize, n as usize))
         }
-        _=> panic!("caller_pc must be vloc")
+        _=> Err(VmError::TypeError(who))
     }
 }
-fn eval_branch(s: &mut State){ // function to branch to given target if second value b on stack is true, otherwise do nothing
-    let new_pc_loc = s.stack.pop().expect("Nothing to pop off stack -- Branch"); // target to be branched to
-    let determine = s.stack.pop().expect("No secondary value to pop off the stack -- Branch") ; // vbool b which determines
 
-    match new_pc_loc{
-        Val::Vloc(target) => { // must be of type vloc
-                match determine{
-                    Val::Vbool(y) =>{ // must be of type vbool
+fn eval_len(s: &mut State) -> Result<(), VmError> { // function to push the length of the object at the Vaddr on top of the stack
+    let addr = s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to pop -- Len"))?;
+    let (_, len) = object_len(s, addr, "Len requires a Vaddr at top of stack")?;
+    s.stack.push(Word::i32(len));
+    Ok(())
+}
+
+fn eval_array_copy(s: &mut State) -> Result<(), VmError> { // function to copy count values between two heap objects, overlapping ranges are fine
+    let count = s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to pop -- ArrayCopy"))?; // count
+    let dst_idx = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop -- ArrayCopy"))?; // j
+    let dst = s.stack.pop().ok_or(VmError::StackUnderflow("No tertiary value to pop -- ArrayCopy"))?; // dst
+    let src_idx = s.stack.pop().ok_or(VmError::StackUnderflow("No fourth value to pop -- ArrayCopy"))?; // i
+    let src = s.stack.pop().ok_or(VmError::StackUnderflow("No fifth value to pop -- ArrayCopy"))?; // src
+
+    let (src_base, src_len) = object_len(s, src, "ArrayCopy requires a Vaddr source")?;
+    let (dst_base, dst_len) = object_len(s, dst, "ArrayCopy requires a Vaddr destination")?;
+    let (from, n) = check_range(s, src_base, src_len, src_idx, count, "ArrayCopy requires i32 indices and count")?;
+    let (to, _) = check_range(s, dst_base, dst_len, dst_idx, count, "ArrayCopy requires i32 indices and count")?;
+    s.heap.copy_within(from..from + n, to); // handles overlapping ranges
+    Ok(())
+}
+
+fn eval_array_fill(s: &mut State) -> Result<(), VmError> { // function to write the same value to a range of a heap object
+    let val_to_be_stored = s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to pop -- ArrayFill"))?; // v
+    let count = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop -- ArrayFill"))?; // count
+    let start = s.stack.pop().ok_or(VmError::StackUnderflow("No tertiary value to pop -- ArrayFill"))?; // i
+    let addr = s.stack.pop().ok_or(VmError::StackUnderflow("No fourth value to pop -- ArrayFill"))?; // arr
+
+    let (base, len) = object_len(s, addr, "ArrayFill requires a Vaddr")?;
+    let (from, n) = check_range(s, base, len, start, count, "ArrayFill requires an i32 index and count")?;
+    for k in from..from + n {
+        s.heap[k] = val_to_be_stored;
+    }
+    Ok(())
+}
+
+fn eval_call_native(x: u32, s: &mut State) -> Result<(), VmError> { // function to pop a native's arguments, call it and push what it returns
+    let native = match s.natives.get(x as usize){
+        Some(n) => n.clone(),
+        None => return Err(VmError::TypeError("No native function at that index -- CallNative"))
+    };
+    if s.stack.len() < native.arity {
+        return Err(VmError::StackUnderflow("Not enough arguments -- CallNative"));
+    }
+    let first_arg = s.stack.len() - native.arity;
+    let args: Vec<Val> = s.stack.split_off(first_arg).into_iter().map(Word::val).collect(); // arguments in the order they were pushed
+    let result = (native.f)(s, &args)?;
+    if s.stack.len() > 1024 {
+        return Err(VmError::StackOverflow);
+    }
+    s.stack.push(Word::from(result));
+    Ok(())
+}
+
+fn eval_ret(s: &mut State) -> Result<(), VmError> { // function to pop the frame record, drop the callee's stack slots and go back to the caller
+    let ret_val = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop -- Ret function"))?;
+    let frame = s.control.pop().ok_or(VmError::StackUnderflow("No frame to return from -- Ret function"))?;
+    match frame.ret {
+        Some(caller_pc) => {
+            s.stack.truncate(s.fp as usize); // the arguments, the saved fp and return address slots and any locals
+            s.fp = frame.fp;
+            s.pc = caller_pc;
+            s.stack.push(ret_val);
+        }
+        None => return Err(VmError::TypeError("Ret from a frame that was never called"))
+    }
+    Ok(())
+}
+fn eval_branch(s: &mut State) -> Result<(), VmError> { // function to branch to given target if second value b on stack is true, otherwise do nothing
+    let new_pc_loc = s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to pop off stack -- Branch"))?; // target to be branched to
+    let determine = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop off the stack -- Branch"))? ; // vbool b which determines
+
+    match new_pc_loc.as_loc(){
+        Some(target) => { // must be of type vloc
+                match determine.as_bool(){
+                    Some(y) =>{ // must be of type vbool
                             match y{
                                 true => s.pc = target,
                                 false => ()
                             }
                     }
-                    _=> panic!("Secondary value must be of type Vbool -- Branch")
+                    None => return Err(VmError::TypeError("Secondary value must be of type Vbool -- Branch"))
                 }
         }
-        _=> panic!("Top value on stack must be a vloc -- Branch")
+        None => return Err(VmError::TypeError("Top value on stack must be a vloc -- Branch"))
+    }
+    Ok(())
+}
+fn eval_read_char(s: &mut State) -> Result<(), VmError> { // function to push the next input byte, or Vunit once the input is exhausted
+    match s.input.next_byte(){
+        Some(b) => s.stack.push(Word::i32(b as i32)),
+        None => s.stack.push(Word::UNIT)
+    }
+    Ok(())
+}
+
+fn eval_print(s: &mut State) -> Result<(), VmError> { // function to pop an i32 and write it to stdout as a byte
+    let c = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop -- Print"))?;
+    match c.as_i32() {
+        Some(c) if (0..=255).contains(&c) => {
+            io::stdout().write_all(&[c as u8]).map_err(|_| VmError::TypeError("Could not write -- Print"))?;
+            Ok(())
+        }
+        _ => Err(VmError::TypeError("Expected a byte -- Print"))
     }
 }
-fn evaluate (i: Instr, s: &mut State){ // function to evaluate the given instruction and match it with correct helper function / set of instructions
-    match i {
-         Instr::Push(x) => { // pushes value onto stack if stack isnt greater than 1024
+
+fn eval_read_int(s: &mut State) -> Result<(), VmError> { // function to parse the next integer from the input, or push Vunit once the input is exhausted
+    'skiploop: loop{ // skip leading whitespace
+        match s.input.peek_byte(){
+            Some(b) if (b as char).is_ascii_whitespace() => { s.input.next_byte(); }
+            _ => break 'skiploop
+        }
+    }
+    let mut text = String::new();
+    match s.input.peek_byte(){
+        None => { // nothing left to read
+            s.stack.push(Word::UNIT);
+            return Ok(());
+        }
+        Some(b'-') | Some(b'+') => {
+            text.push(s.input.next_byte().unwrap() as char);
+        }
+        _ => ()
+    }
+    'digitloop: loop{ // collect digits until the first non-digit
+        match s.input.peek_byte(){
+            Some(b) if (b as char).is_ascii_digit() => { text.push(s.input.next_byte().unwrap() as char); }
+            _ => break 'digitloop
+        }
+    }
+    match text.parse::<i32>(){
+        Ok(i) => s.stack.push(Word::i32(i)),
+        Err(_) => return Err(VmError::BadInput("Input is not a valid i32 -- ReadInt"))
+    }
+    Ok(())
+}
+
+fn unwind(s: &mut State, e: VmError) -> Result<(), VmError> { // function to transfer control to the innermost handler, or hand the error back if there is none
+    match s.handlers.pop(){
+        Some(h) => {
+            s.stack.truncate(h.sp); // drop everything pushed since the handler was installed
+            s.control.truncate(h.frames);
+            s.fp = h.fp;
+            s.stack.push(Word::from(e.to_val()));
+            s.pc = h.target;
+            Ok(())
+        }
+        None => Err(e)
+    }
+}
+
+fn evaluate (i: &Instr, s: &mut State) -> Result<(), VmError> { // function to evaluate the given instruction and match it with correct helper function / set of instructions
+    match *i {
+         Instr::Push(ref x) => { // pushes value onto stack if stack isnt greater than 1024
             if(s.stack.len() > 1024){
-                panic!("Stack size exceeded");
+                return Err(VmError::StackOverflow);
             }
             else{
-                s.stack.push(x);
+                s.stack.push(Word::from(x));
             }
          }
          Instr::Pop => { // removes top value on stack if stack is populated
-                s.stack.pop().expect("Nothing to pop");
+                s.stack.pop().ok_or(VmError::StackUnderflow("Nothing to pop"))?;
          }
          Instr::Peek(x) =>{ // copies the value at x'th location onto top of stack
-                let convert_x_to_usize: usize = x.try_into().unwrap();
-                let copy_at_ith = s.stack[convert_x_to_usize].clone();
+                let convert_x_to_usize = x as usize;
+                if convert_x_to_usize >= s.stack.len() {
+                    return Err(VmError::BadStackIndex("Peek index out of bound"));
+                }
+                let copy_at_ith = s.stack[convert_x_to_usize];
                 s.stack.push(copy_at_ith);
          }
-         Instr::Unary(x) =>{ // negation operator applied to top value on stack
-                eval_unary(s);
+         Instr::Unary(ref x) =>{ // negation operator applied to top value on stack
+                eval_unary(s)?;
          }
-         Instr::Binary(x) =>{ // calls binary helper function
-                let result = eval_binary(x,s);
+         Instr::Binary(ref x) =>{ // calls binary helper function
+                eval_binary(x,s)?;
          }
          Instr::Swap => {  // swaps the top two values on the stack
-                let top_value = s.stack.pop().expect("No value to pop"); // pop the top value
-                let second_top_value = s.stack.pop().expect("No secondary value to pop"); // pop the second to top value
+                let top_value = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop"))?; // pop the top value
+                let second_top_value = s.stack.pop().ok_or(VmError::StackUnderflow("No secondary value to pop"))?; // pop the second to top value
                 s.stack.push(top_value); // push the top value back on so its now secondary top
                 s.stack.push(second_top_value); // push the secondary top value on so its now top
          }
          Instr::Alloc => { // calls alloc helper function
-                eval_alloc(s);
+                eval_alloc(s)?;
          }
         Instr::Set => { // calls set helper function
-            eval_set(s);
+            eval_set(s)?;
          }
          Instr::Get => { // calls get helper function
-                eval_get(s);
+                eval_get(s)?;
          }
          Instr::Var(x) => { // pushes onto stack the value at frame pointer + x 
-                let index_to_find = s.fp + x;
-                if index_to_find as usize > s.stack.len() - 1{
-                        panic!("Var index out of bound");
+                let index_to_find = s.fp as usize + x as usize;
+                if index_to_find >= s.stack.len() {
+                        return Err(VmError::BadStackIndex("Var index out of bound"));
                 }
-                let val_to_push = s.stack[(index_to_find as usize)].clone();
+                let val_to_push = s.stack[index_to_find];
                 s.stack.push(val_to_push);
          }
          Instr::Store(x) => { // overwrites the value at stack address frame pointer + 1 with top value on stack
-                let stack_top = s.stack.pop().expect("No value to pop -- Store function"); // top value on the stack is popped so we know what it is
-                let stack_top_clone = stack_top.clone();
+                let stack_top = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop -- Store function"))?; // top value on the stack is popped so we know what it is
+                let stack_top_clone = stack_top;
     
-                let index_to_be_overwritten = s.fp + x ; // points to the index we're overwriting with top stack clone value
-                if index_to_be_overwritten > s.stack.len() as u32 - 1 {
-                    panic!("Store is going out of bounds");                
+                let index_to_be_overwritten = s.fp as usize + x as usize; // points to the index we're overwriting with top stack clone value
+                if index_to_be_overwritten >= s.stack.len() {
+                    return Err(VmError::BadStackIndex("Store is going out of bounds"));
                 }
-                let index_to_be_overwritten_usize = index_to_be_overwritten as usize; // convert to usize so we can index
-                s.stack[index_to_be_overwritten_usize] = stack_top_clone;
+                s.stack[index_to_be_overwritten] = stack_top_clone;
          }
-        Instr::SetFrame(x) => { // sets the frame pointer according to given argument 
-            s.stack.push(Val::Vloc(s.fp));
+        Instr::SetFrame(x) => { // sets the frame pointer according to given argument, the old one goes in a new frame record
+            if x as usize > s.stack.len() {
+                return Err(VmError::BadStackIndex("SetFrame passes more values than the stack holds"));
+            }
+            s.stack.push(Word::loc(s.fp)); // the slot old programs number their locals after, Ret never reads it
+            s.control.push(Frame{fp: s.fp, ret: None});
             let x_as_usize = x as usize;
             s.fp = (s.stack.len() - x_as_usize - 1) as u32;
         }
         Instr::Call => { // jumps to instructions at vloc on top of stack
-            let x = s.stack.pop().unwrap(); 
-            match x {
-                Val::Vloc(a) => { // top of stack value must be vloc
-                    s.stack.push(Val::Vloc(s.pc));
+            let x = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop -- Call"))?; 
+            match x.as_loc() {
+                Some(a) => { // top of stack value must be vloc
+                    s.stack.push(Word::loc(s.pc)); // kept for the same reason as SetFrame's slot
+                    match s.control.last_mut() {
+                        Some(Frame{ret: ref mut ret @ None, ..}) => *ret = Some(s.pc), // the frame SetFrame opened
+                        _ => s.control.push(Frame{fp: s.fp, ret: Some(s.pc)}) // a Call without a SetFrame keeps fp
+                    }
                     s.pc = a;
                 }
-                _ => panic!("Illegal use of call function") // if top value on stack isnt a vloc
+                None => return Err(VmError::TypeError("Illegal use of call function")) // if top value on stack isnt a vloc
             }
          }
         Instr::Ret => {  // calls ret helper function
-                eval_ret(s);
+                eval_ret(s)?;
 
          }
          Instr::Branch => { // calls branch helper function
-                eval_branch(s);
+                eval_branch(s)?;
          }
          Instr::Halt => { // gives the state the flag to halt the program
             s.halt = true;
          }
-        _ => {
-            ();
-        }
+         Instr::ReadChar => { // calls read char helper function if the stack has room
+            if s.stack.len() > 1024 {
+                return Err(VmError::StackOverflow);
+            }
+            eval_read_char(s)?;
+         }
+         Instr::ReadInt => { // calls read int helper function if the stack has room
+            if s.stack.len() > 1024 {
+                return Err(VmError::StackOverflow);
+            }
+            eval_read_int(s)?;
+         }
+         Instr::Print => { // calls print helper function
+            eval_print(s)?;
+         }
+         Instr::PushHandler(x) => { // records where to resume, and the frame and stack height to unwind to
+            s.handlers.push(Handler{target: x, fp: s.fp, sp: s.stack.len(), frames: s.control.len()});
+         }
+         Instr::PopHandler => { // removes the innermost handler
+            s.handlers.pop().ok_or(VmError::NoHandler)?;
+         }
+         Instr::Throw => { // throws the top value on the stack
+            let thrown = s.stack.pop().ok_or(VmError::StackUnderflow("No value to pop -- Throw"))?;
+            return Err(VmError::Thrown(thrown.val()));
+         }
+         Instr::Len => { // calls len helper function
+            eval_len(s)?;
+         }
+         Instr::ArrayCopy => { // calls array copy helper function
+            eval_array_copy(s)?;
+         }
+         Instr::ArrayFill => { // calls array fill helper function
+            eval_array_fill(s)?;
+         }
+         Instr::CallNative(x) => { // calls call native helper function
+            eval_call_native(x, s)?;
+         }
+         Instr::PushConst(_) => { // Program::load_data turns these into Pushes of the object's address
+            return Err(VmError::TypeError("PushConst before the program's data was loaded"));
+         }
+         Instr::AddImm(c) => { // adds c to the top value, failing the same way the Push would have
+            if s.stack.len() > 1024 {
+                return Err(VmError::StackOverflow);
+            }
+            s.stack.push(Word::i32(c));
+            eval_binary(&Binop::Add, s)?;
+         }
+         Instr::VarVarBinary(x, y, ref b) => { // the two Vars push exactly what they would have before the Binary
+            evaluate(&Instr::Var(x), s)?;
+            evaluate(&Instr::Var(y), s)?;
+            eval_binary(b, s)?;
+         }
+         Instr::Jump(x) => { // unconditional jump, failing the same way the two Pushes would have
+            if s.stack.len() > 1023 {
+                return Err(VmError::StackOverflow);
+            }
+            s.pc = x;
+         }
+    }
+    Ok(())
+}
+
+fn fetch<'a>(program: &'a [Instr], s: &mut State) -> Result<&'a Instr, VmError> { // function to borrow the instruction at pc and move pc on to the next one
+    let pc = s.pc; // setting the program counter 
+    s.pc = pc + 1; // setting the state's program counter to next instruction 
+    match program.get(pc as usize){ // checks to ensure pc isnt out of bounds
+        Some(i) => Ok(i),
+        None => Err(VmError::BadPc(pc))
     }
 }
 
-fn exec(s: &mut State, instruc_vec: Vec<Instr>){  // function to execute the main loop of our program
-    let mut counter = 0;
+fn step(i: &Instr, s: &mut State) -> Result<(), VmError> { // function to evaluate one instruction, sending any error to the innermost handler
+    match evaluate(i, s){ // sends current instruction and state into evaluate function
+        Ok(()) => Ok(()),
+        Err(e) => unwind(s, e) // errors go to the innermost handler, and come back if there is none
+    }
+}
+
+fn exec(program: &Program, s: &mut State){  // function to execute the main loop of our program, the program is only ever borrowed
     'mainloop: loop{ // loop to iterate through every instruction in our program
         if s.halt { break 'mainloop } // check to see if program has been given the halt signal, if so exit
-        let pc = s.pc; // setting the program counter 
-        s.pc = pc + 1; // setting the state's program counter to next instruction 
-        if pc >= TryInto::<u32>::try_into(s.program.len()).unwrap(){ // checks to ensure pc isnt out of bounds
-            panic!("exec: pc out of bounds")
+        let pc = s.pc;
+        let i = match fetch(&program.code, s){
+            Ok(i) => i,
+            Err(e) => panic!("exec: {}", e)
+        };
+
+        if let Err(e) = step(i, s) { // abort the program if nothing handled the error, saying how it got there
+            panic!("{}\n{}", e, debuginfo::backtrace(program, s, pc));
         }
-        let pcusize = pc as usize;
-        let i = s.program[pcusize].clone();
-        let newi = i.clone();
+     }
+    let result = s.stack.pop().unwrap();
+    print!("{:?}",result);
+}
+
+pub fn read_name(bytes: &mut Iter<u8>) -> String { // function to read a u32 byte length followed by that many bytes of UTF-8
+    let len = <u32 as fromBinary>::from_binary(bytes);
+    let name: Vec<u8> = bytes.take(len as usize).cloned().collect();
+    String::from_utf8(name).expect("Bad! Name is not UTF-8")
+}
+
+pub fn read_imports(bytes: &mut Iter<u8>) -> Vec<String> { // function to read a u32 count of native names
+    let count = <u32 as fromBinary>::from_binary(bytes);
+    (0..count).map(|_| read_name(bytes)).collect()
+}
+
+pub fn read_exports(bytes: &mut Iter<u8>) -> Vec<(String, u32)> { // function to read a u32 count of exports, each a name followed by the u32 location of its function
+    let count = <u32 as fromBinary>::from_binary(bytes);
+    let mut exports = Vec::new();
+    for _ in 0..count {
+        let name = read_name(bytes);
+        let loc = <u32 as fromBinary>::from_binary(bytes);
+        exports.push((name, loc));
+    }
+    exports
+}
 
-        println!("{}",i);
-        for x in s.stack.clone(){
-            println!("{:?}",x);
+impl fromBinary for Program { // function to convert binary into a program: a sectioned container, or the legacy layout of instructions then the optional import and export tables
+    fn from_binary(bytes: &mut Iter<u8>) -> Self {
+        if object::is_container(bytes) {
+            return object::read_object(bytes);
+        }
+        let buffer = <u32 as fromBinary>::from_binary(bytes); // buffer to hold instructions
+        let mut code: Vec<Instr> = Vec::new(); // initalize our instruction vector
+        for _ in 0..buffer { // loop through our buffer and push each instruction into our vector 
+            code.push(Instr::from_binary(bytes)); 
+        }
+        let mut imports = Vec::new();
+        if bytes.len() != 0 { // older programs end right after their instructions
+            imports = read_imports(bytes);
+        }
+        let mut exports = Vec::new();
+        if bytes.len() != 0 {
+            exports = read_exports(bytes);
         }
-        println!("{}","-------------");
+        Program{code, imports, exports, constants: Vec::new(), debug: None, heap: Vec::new(), relocations: None}
+    }
+}
 
-        evaluate(i, s); // sends current instruction and state into evaluate function
-     }
-    // let result = s.stack.pop().unwrap();
-    // print!("{:?}",result);
+fn expect_i32(v: &Val) -> Result<i32, VmError> { // helper for natives that only work on i32 arguments
+    match v {
+        Val::Vi32(i) => Ok(*i),
+        _=> Err(VmError::TypeError("Native function expected a Vi32"))
+    }
+}
+
+pub fn register_std_natives(s: &mut State){ // function to register the natives every program run from the command line gets
+    s.register_native("log", 1, |_, args| { // writes its argument to stderr so it doesn't mix with the result
+        eprintln!("{:?}", args[0]);
+        Ok(Val::Vunit)
+    });
+    s.register_native("abs", 1, |_, args| Ok(Val::Vi32(expect_i32(&args[0])?.abs())));
+    s.register_native("min", 2, |_, args| Ok(Val::Vi32(expect_i32(&args[0])?.min(expect_i32(&args[1])?))));
+    s.register_native("max", 2, |_, args| Ok(Val::Vi32(expect_i32(&args[0])?.max(expect_i32(&args[1])?))));
 }
 
 fn main() {
@@ -552,13 +977,224 @@ fn main() {
     let query = args[1].clone(); // query holds the command line argument
     let binaryvec = fs::read(query).expect("Wrong file"); // reads in our file into a binary vector 
     let mut iter = binaryvec.iter(); // iterator to traverse our binary vector 
-    let buffer = <u32 as fromBinary>::from_binary(iter.by_ref()); // buffer to hold instructions
-    let mut instruc_vec: Vec<Instr> = Vec::new(); // initalize our instruction vector
-    for i in 0..buffer { // loop through our buffer and push each instruction into our vector 
-        instruc_vec.push(Instr::from_binary(iter.by_ref())); 
+    let mut program = Program::from_binary(iter.by_ref());
+
+    let mut flag = 2; // options come after the file name
+    let mut jit_threshold: Option<Option<u32>> = None;
+    let mut opt_level = 0;
+    let mut profile = profile::ProfileOptions::default();
+    let mut profiling = false;
+    let mut coverage: Option<String> = None;
+    let mut debugging: Option<Option<String>> = None;
+    let mut checkpoint: Option<String> = None;
+    let mut checkpoint_every = snapshot::CHECKPOINT_EVERY;
+    let mut resume: Option<String> = None;
+    let mut tracing = false;
+    let mut links: Vec<String> = Vec::new();
+    let mut relocatable = false;
+    let mut input: Option<String> = None;
+    'flagloop: loop{
+        if args.len() > flag && args[flag].starts_with("--jit") { // vm file.o --jit[=calls before compiling] ...
+            jit_threshold = match args[flag].split_once('=').map(|(_, v)| v){
+                Some(n) => Some(Some(n.parse::<u32>().expect("--jit=N needs a number of calls"))),
+                None => Some(None)
+            };
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--profile") { // vm file.o --profile[=report.json] ...
+            profile.report = true;
+            profile.json = args[flag].split_once('=').map(|(_, path)| path.to_string());
+            profiling = true;
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--folded") { // vm file.o --folded[=stacks.txt] ...
+            profile.folded = Some(args[flag].split_once('=').map(|(_, path)| path.to_string()));
+            profiling = true;
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--debug") { // vm file.o --debug[=commands.txt] ...
+            debugging = Some(args[flag].split_once('=').map(|(_, path)| path.to_string()));
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--coverage") { // vm file.o --coverage[=file.info] ...
+            let stem = args[1].trim_end_matches(".o");
+            coverage = Some(match args[flag].split_once('=').map(|(_, v)| v){
+                Some(path) => path.to_string(),
+                None => format!("{}.info", stem)
+            });
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--checkpoint=") { // vm file.o --checkpoint=file.snap ...
+            checkpoint = Some(args[flag]["--checkpoint=".len()..].to_string());
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--every=") { // vm file.o --checkpoint=file.snap --every=instructions ...
+            checkpoint_every = args[flag]["--every=".len()..].parse::<u64>().expect("--every=N needs a number of instructions").max(1);
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--resume=") { // vm file.o --resume=file.snap ...
+            resume = Some(args[flag]["--resume=".len()..].to_string());
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--link=") { // vm main.o --link=lib.o ...
+            links.push(args[flag]["--link=".len()..].to_string());
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag] == "--relocatable" { // vm file.o --relocatable --write-object lib.o, marks its code addresses
+            relocatable = true;
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--input=") { // vm file.o --input=text, ReadChar and ReadInt read text instead of stdin
+            input = Some(args[flag]["--input=".len()..].to_string());
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag] == "--trace" { // vm file.o --trace ...
+            tracing = true;
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag].starts_with("--sample=") { // vm file.o --folded --sample=microseconds ...
+            let us = args[flag]["--sample=".len()..].parse::<u64>().expect("--sample=US needs a number of microseconds");
+            profile.sample = Some(std::time::Duration::from_micros(us));
+            flag += 1;
+        }
+        else if args.len() > flag && args[flag] == "--counts-only" { // vm file.o --profile --counts-only, no wall times in the reports
+            profile.counts_only = true;
+            flag += 1;
+        }
+        else if args.len() > flag + 1 && args[flag] == "--opt-level" { // vm file.o --opt-level N ...
+            opt_level = args[flag + 1].parse::<u32>().expect("--opt-level needs a number");
+            flag += 2;
+        }
+        else {
+            break 'flagloop;
+        }
+    }
+    if relocatable && program.relocations.is_none() {
+        program.relocations = Some(link::infer_relocations(&program));
+    }
+    let unresolved = program.relocations.as_ref().is_some_and(|r| !r.externals.is_empty());
+    if !links.is_empty() || unresolved { // link the libraries in after the program, resolving its external symbols
+        let mut objects = vec![(args[1].clone(), program)];
+        for path in links.iter() {
+            let bytes = fs::read(path).expect("Could not read the object file to link");
+            objects.push((path.clone(), Program::from_binary(&mut bytes.iter())));
+        }
+        program = match link::link(&objects) {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e)
+        };
+    }
+    if args.len() > flag + 1 && args[flag] == "--write-object" { // vm file.o --write-object out.o, rewrites the program as a sectioned container
+        fs::write(&args[flag + 1], object::write_object(&program)).expect("Could not write the object file");
+        return;
+    }
+    let mut program = match opt::optimize(&program, opt_level) {
+        Ok(p) => p,
+        Err(e) => panic!("{}", e)
+    };
+    let mut s = State::new(); // initalize our state
+    register_std_natives(&mut s);
+    if let Some(text) = input {
+        s.input = Input::Buffer(text.into_bytes(), 0);
+    }
+    if let Err(e) = program.link_natives(&s.natives) { // natives the program refers to by name
+        panic!("{}", e);
+    }
+    s.heap = match program.load_data() { // the heap section and constant objects, if the object file has them
+        Ok(heap) => heap,
+        Err(e) => panic!("{}", e)
+    };
+    if let Some(ref path) = resume { // carry on from a snapshot instead of the start
+        let bytes = fs::read(path).expect("Could not read the snapshot");
+        if let Err(e) = snapshot::restore(&mut s, &bytes, &program) {
+            panic!("{}", e);
+        }
+    }
+
+    if args.len() > flag && args[flag] == "--disassemble" { // vm file.o --disassemble, lists the program as it would run
+        print!("{}", debuginfo::disassemble(&program));
+        return;
+    }
+    if args.len() > flag && args[flag] == "--cfg" { // vm file.o --cfg [out.dot], the control-flow graph for Graphviz
+        let dot = cfg::to_dot(&program, &cfg::build(&program));
+        match args.get(flag + 1){
+            Some(path) => fs::write(path, dot).expect("Could not write the DOT file"),
+            None => print!("{}", dot)
+        }
+        return;
+    }
+    if args.len() > flag && args[flag] == "--stack" { // vm file.o --stack, how much stack each function needs
+        print!("{}", stack::report(&stack::analyze(&program, &s.natives)));
+        return;
+    }
+    if args.len() > flag && args[flag] == "--isa" { // vm file.o --isa, the instruction set reference as markdown
+        print!("{}", isa::reference());
+        return;
+    }
+    if args.len() > flag && args[flag] == "--compile-c" { // vm file.o --compile-c [out.c]
+        let names: Vec<String> = s.natives.iter().map(|n| n.name.clone()).collect();
+        let heap: Vec<Val> = s.heap.iter().map(|w| w.val()).collect(); // what load_data laid out
+        let c = match cgen::compile_c(&program.code, &heap, &names){
+            Ok(c) => c,
+            Err(e) => panic!("{}", e)
+        };
+        match args.get(flag + 1){
+            Some(path) => fs::write(path, c).expect("Could not write the C file"),
+            None => print!("{}", c)
+        }
+        return;
+    }
+    if args.len() > flag + 1 && args[flag] == "--call" { // vm file.o --call <name or location> [i32 arguments...]
+        let entry = match args[flag + 1].parse::<u32>(){
+            Ok(l) => Entry::Loc(l),
+            Err(_) => Entry::Symbol(&args[flag + 1])
+        };
+        let call_args: Vec<Val> = args[flag + 2..].iter()
+            .map(|a| Val::Vi32(a.parse::<i32>().expect("--call arguments must be i32s")))
+            .collect();
+        match s.call(&program, entry, &call_args){
+            Ok(v) => print!("{:?}", v),
+            Err(e) => panic!("{}", e)
+        }
+        return;
+    }
+
+    if let Some(ref commands) = debugging { // the debugger steps the interpreter itself
+        debug::debug(&program, &mut s, commands.as_ref().map(|c| c.as_str()));
+        return;
+    }
+    if let Some(ref tracefile) = coverage { // coverage is also taken in the interpreter, against a listing of the program
+        let listing = format!("{}.lst", args[1].trim_end_matches(".o"));
+        coverage::exec_coverage(&program, &mut s, tracefile, &listing);
+        return;
+    }
+    if let Some(ref path) = checkpoint { // checkpoints are taken between interpreted instructions
+        snapshot::exec_checkpointed(&program, &mut s, path, checkpoint_every);
+        return;
+    }
+    if tracing { // each instruction to stderr as the interpreter runs it
+        debuginfo::exec_trace(&program, &mut s);
+        return;
+    }
+    if profiling { // profiles are always taken in the interpreter
+        profile::exec_profile(&program, &mut s, &profile);
+        return;
+    }
+
+    #[cfg(feature = "jit")]
+    {
+        if let Some(threshold) = jit_threshold {
+            let mut j = jit::Jit::new(&program.code, threshold.unwrap_or(jit::JIT_THRESHOLD));
+            jit::exec_jit(&mut s, &mut j);
+            return;
+        }
+    }
+    #[cfg(not(feature = "jit"))]
+    {
+        if jit_threshold.is_some() {
+            panic!("This vm was built without the jit feature");
+        }
     }
-    let mut s: State = State{halt: false,  pc: 0, fp: 0, stack: Vec::<Val>::with_capacity(0), // initalize our state
-                         heap: Vec::<Val>::with_capacity(0), program: instruc_vec.clone()};
 
-    exec(&mut s, instruc_vec); // call our execution loop on our state and instruction vector 
+    exec(&program, &mut s); // call our execution loop on our instructions and state
 }
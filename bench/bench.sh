@@ -0,0 +1,34 @@
+#!/bin/bash
+# Times every benchmark program, best of RUNS runs. Set BASELINE to another vm binary
+# (for example one built from an older commit) to time it on the same programs.
+#   ./bench.sh                      times ../target/release/vm
+#   BASELINE=/tmp/old-vm ./bench.sh  also times /tmp/old-vm and prints the speedup
+
+VM=${VM:-../target/release/vm}
+RUNS=${RUNS:-5}
+
+best() { # prints the fastest of RUNS runs of "$@" in milliseconds
+    local min=""
+    for r in `seq $RUNS`;
+    do
+	local start=`date +%s%N`
+	"$@" > /dev/null
+	local end=`date +%s%N`
+	local ms=$(( (end - start) / 1000000 ))
+	if [ -z "$min" ] || [ $ms -lt $min ]; then
+	    min=$ms
+	fi
+    done
+    echo $min
+}
+
+for f in *.o;
+do
+    NEW=`best $VM $f`
+    if [ -n "$BASELINE" ]; then
+	OLD=`best $BASELINE $f`
+	printf "%-16s %6s ms  (baseline %6s ms, %s.%02dx)\n" $f $NEW $OLD $(( OLD / NEW )) $(( OLD * 100 / NEW % 100 ))
+    else
+	printf "%-16s %6s ms\n" $f $NEW
+    fi
+done
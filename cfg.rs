@@ -0,0 +1,319 @@
+// Control-flow graph, for `vm file.o --cfg [out.dot]`
+// The code is cut into basic blocks before every jump target and after every Branch, Jump, Call,
+// Ret, Halt and Throw. Jump targets are the Vlocs the program pushes, handler targets and exports.
+// Branch and Call take their target from the stack, so each block is run over a small model of the
+// stack that only knows which values are Push(Vloc)s and Push(Vbool)s. That is enough for the
+// sequences compilers targeting the vm emit, `Push(Vloc(f)); SetFrame(n); Swap; Call` among them,
+// and a Branch on a pushed Vbool only gets the edge it always takes. A Vloc that is not used up by
+// a Branch or Call is taken to be a function pointer.
+// Functions start at pc 0, at exports, at known Call targets and at function pointers. Each one owns
+// the blocks it reaches without following calls. Ret blocks get an edge back to the return site of
+// every known Call of their function, and a Call that takes its target from elsewhere gets none.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use super::{Program, Instr, Val};
+use isa::{self, Effect};
+use debuginfo::function_name;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum EdgeKind {
+    Fallthrough, //Into the next block
+    Taken,       //A Branch to its target
+    NotTaken,    //A Branch falling through
+    Jump,        //A Jump to its target
+    Call,        //A Call to the function it calls
+    AfterCall,   //From a Call to its return site, standing in for the call
+    Return,      //A Ret to the return site of a Call of its function
+    Handler      //A PushHandler to the handler it installs
+}
+
+#[derive(Debug,Clone)]
+pub struct Edge {
+    pub to: usize, //Index into blocks
+    pub kind: EdgeKind
+}
+
+#[derive(Debug,Clone)]
+pub struct Block {
+    pub start: u32,       //The first pc of the block
+    pub end: u32,         //One past its last pc
+    pub succs: Vec<Edge>
+}
+
+#[derive(Debug,Clone)]
+pub struct Function {
+    pub entry: u32,
+    pub name: String,
+    pub blocks: Vec<usize> //The blocks reached from entry without following calls, entry's first
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub functions: Vec<Function>,
+    pub calls: Vec<(u32, Option<u32>)>,   //Every Call's pc and the function it calls, if known
+    pub branches: Vec<(u32, Option<u32>)> //Every Branch's pc and where it jumps, if known
+}
+
+impl EdgeKind {
+    pub fn intraprocedural(self) -> bool { // does the edge stay in the function?
+        !matches!(self, EdgeKind::Call | EdgeKind::Return)
+    }
+}
+
+impl Cfg {
+    pub fn block_at(&self, pc: u32) -> Option<usize> { // the block holding pc
+        match self.blocks.binary_search_by(|b| b.start.cmp(&pc)) {
+            Ok(k) => Some(k),
+            Err(0) => None,
+            Err(k) => if pc < self.blocks[k - 1].end { Some(k - 1) } else { None }
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+enum Slot {
+    Loc(u32),   //A Vloc pushed by Push
+    Bool(bool), //A Vbool pushed by Push
+    Other
+}
+
+struct Uses { // what the stack model found out about one block
+    branch: Option<u32>,     //The target of the Branch ending the block, if known
+    condition: Option<bool>, //Whether it is taken, if known
+    call: Option<u32>,       //The target of the Call ending the block, if known
+    escaped: Vec<u32>        //Vlocs pushed but not used up by a Branch or Call
+}
+
+fn pop(stack: &mut Vec<Slot>, escaped: &mut Vec<u32>) -> Slot {
+    let slot = stack.pop().unwrap_or(Slot::Other);
+    if let Slot::Loc(l) = slot {
+        escaped.push(l);
+    }
+    slot
+}
+
+fn model(code: &[Instr], start: u32, end: u32) -> Uses { // function to run a block over a stack that only tracks pushed Vlocs and Vbools
+    let mut stack: Vec<Slot> = Vec::new();
+    let mut uses = Uses{branch: None, condition: None, call: None, escaped: Vec::new()};
+    for i in code[start as usize..end as usize].iter() {
+        match *i {
+            Instr::Push(Val::Vloc(l)) => stack.push(Slot::Loc(l)),
+            Instr::Push(Val::Vbool(b)) => stack.push(Slot::Bool(b)),
+            Instr::Swap => {
+                let a = stack.pop().unwrap_or(Slot::Other);
+                let b = stack.pop().unwrap_or(Slot::Other);
+                stack.push(a);
+                stack.push(b);
+            }
+            Instr::Branch => {
+                if let Some(Slot::Loc(l)) = stack.pop() {
+                    uses.branch = Some(l);
+                }
+                if let Slot::Bool(b) = pop(&mut stack, &mut uses.escaped) {
+                    uses.condition = Some(b);
+                }
+            }
+            Instr::Call => {
+                if let Some(Slot::Loc(l)) = stack.pop() {
+                    uses.call = Some(l);
+                }
+                stack.push(Slot::Other);
+            }
+            _ => match isa::opcode(i).effect {
+                Effect::Fixed(pops, pushes) => {
+                    for _ in 0..pops {
+                        pop(&mut stack, &mut uses.escaped);
+                    }
+                    for _ in 0..pushes {
+                        stack.push(Slot::Other);
+                    }
+                }
+                Effect::Native | Effect::Return => { // how much is popped isn't known here, so assume everything
+                    while !stack.is_empty() {
+                        pop(&mut stack, &mut uses.escaped);
+                    }
+                    stack.push(Slot::Other);
+                }
+            }
+        }
+    }
+    while !stack.is_empty() { // left for a later block
+        pop(&mut stack, &mut uses.escaped);
+    }
+    uses
+}
+
+fn ends_block(i: &Instr) -> bool {
+    matches!(*i, Instr::Branch | Instr::Jump(_) | Instr::Call | Instr::Ret | Instr::Halt | Instr::Throw)
+}
+
+pub fn build(program: &Program) -> Cfg { // function to build the control-flow graph of program
+    let code = &program.code;
+    let len = code.len() as u32;
+    let mut leaders: BTreeSet<u32> = BTreeSet::new();
+    leaders.insert(0);
+    for (pc, i) in code.iter().enumerate() {
+        match *i {
+            Instr::Push(Val::Vloc(l)) | Instr::PushHandler(l) | Instr::Jump(l) => { leaders.insert(l); }
+            _ => ()
+        }
+        if ends_block(i) {
+            leaders.insert(pc as u32 + 1);
+        }
+    }
+    for e in program.exports.iter() {
+        leaders.insert(e.1);
+    }
+    let starts: Vec<u32> = leaders.into_iter().filter(|l| *l < len).collect();
+
+    let mut cfg = Cfg::default();
+    for (k, start) in starts.iter().enumerate() {
+        let end = starts.get(k + 1).cloned().unwrap_or(len);
+        cfg.blocks.push(Block{start: *start, end, succs: Vec::new()});
+    }
+
+    let mut entries: Vec<u32> = Vec::new();
+    if len > 0 {
+        entries.push(0);
+    }
+    entries.extend(program.exports.iter().map(|e| e.1));
+    let mut uses: Vec<Uses> = Vec::new();
+    for b in cfg.blocks.iter() {
+        let u = model(code, b.start, b.end);
+        if let Some(l) = u.call {
+            entries.push(l);
+        }
+        entries.extend(u.escaped.iter().cloned());
+        uses.push(u);
+    }
+
+    for (k, u) in uses.iter().enumerate() {
+        let last = cfg.blocks[k].end - 1;
+        let next = if cfg.blocks[k].end < len { cfg.block_at(cfg.blocks[k].end) } else { None };
+        let mut succs: Vec<Edge> = Vec::new();
+        {
+            let mut edge = |to: Option<usize>, kind: EdgeKind| {
+                if let Some(to) = to {
+                    succs.push(Edge{to, kind});
+                }
+            };
+            for pc in cfg.blocks[k].start..cfg.blocks[k].end {
+                if let Instr::PushHandler(l) = code[pc as usize] {
+                    edge(cfg.block_at(l), EdgeKind::Handler);
+                }
+            }
+            match code[last as usize] {
+                Instr::Branch => {
+                    cfg.branches.push((last, u.branch));
+                    if u.condition != Some(false) {
+                        edge(u.branch.and_then(|l| cfg.block_at(l)), EdgeKind::Taken);
+                    }
+                    if u.condition != Some(true) {
+                        edge(next, EdgeKind::NotTaken);
+                    }
+                }
+                Instr::Jump(l) => edge(cfg.block_at(l), EdgeKind::Jump),
+                Instr::Call => {
+                    edge(u.call.and_then(|l| cfg.block_at(l)), EdgeKind::Call);
+                    edge(next, EdgeKind::AfterCall);
+                    cfg.calls.push((last, u.call));
+                }
+                Instr::Ret | Instr::Halt | Instr::Throw => (),
+                _ => edge(next, EdgeKind::Fallthrough)
+            }
+        }
+        cfg.blocks[k].succs = succs;
+    }
+
+    let mut seen: BTreeSet<u32> = BTreeSet::new();
+    for entry in entries.into_iter() {
+        let first = match cfg.block_at(entry) {
+            Some(b) if cfg.blocks[b].start == entry && seen.insert(entry) => b,
+            _ => continue // not a code address, or one already seen
+        };
+        let mut blocks = vec![first];
+        let mut k = 0;
+        while k < blocks.len() {
+            for e in cfg.blocks[blocks[k]].succs.iter() {
+                if e.kind.intraprocedural() && !blocks.contains(&e.to) {
+                    blocks.push(e.to);
+                }
+            }
+            k += 1;
+        }
+        cfg.functions.push(Function{entry, name: function_name(program, entry), blocks});
+    }
+
+    for &(pc, target) in cfg.calls.clone().iter() {
+        let f = match target.and_then(|t| cfg.functions.iter().position(|f| f.entry == t)) {
+            Some(f) => f,
+            None => continue
+        };
+        let site = match cfg.block_at(pc + 1) {
+            Some(b) => b,
+            None => continue // a Call at the very end returns nowhere
+        };
+        for b in cfg.functions[f].blocks.clone().into_iter() {
+            let block = &mut cfg.blocks[b];
+            if let Instr::Ret = code[block.end as usize - 1] {
+                block.succs.push(Edge{to: site, kind: EdgeKind::Return});
+            }
+        }
+    }
+    cfg
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn to_dot(program: &Program, cfg: &Cfg) -> String { // function to write the graph out for Graphviz, one cluster per function
+    let mut out = String::new();
+    writeln!(out, "digraph cfg {{").unwrap();
+    writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+    let node = |out: &mut String, k: usize, indent: &str| {
+        let b = &cfg.blocks[k];
+        let mut label = String::new();
+        for pc in b.start..b.end {
+            write!(label, "{}: {}\\l", pc, escape(&program.code[pc as usize].to_string())).unwrap();
+        }
+        writeln!(out, "{}b{} [label=\"{}\"];", indent, k, label).unwrap();
+    };
+    let mut placed = vec![false; cfg.blocks.len()];
+    for (n, f) in cfg.functions.iter().enumerate() {
+        writeln!(out, "  subgraph cluster_{} {{", n).unwrap();
+        writeln!(out, "    label=\"{}\";", escape(&f.name)).unwrap();
+        for &k in f.blocks.iter() {
+            if !placed[k] {
+                placed[k] = true;
+                node(&mut out, k, "    ");
+            }
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+    for (k, &p) in placed.iter().enumerate() {
+        if !p { // no function reaches it
+            node(&mut out, k, "  ");
+        }
+    }
+    for (k, b) in cfg.blocks.iter().enumerate() {
+        for e in b.succs.iter() {
+            let style = match e.kind {
+                EdgeKind::Fallthrough => "",
+                EdgeKind::Taken => " [label=\"true\"]",
+                EdgeKind::NotTaken => " [label=\"false\"]",
+                EdgeKind::Jump => " [label=\"jump\"]",
+                EdgeKind::Call => " [label=\"call\", style=dashed]",
+                EdgeKind::AfterCall => " [style=dotted]",
+                EdgeKind::Return => " [label=\"ret\", style=dashed]",
+                EdgeKind::Handler => " [label=\"handler\", style=dashed, color=red]"
+            };
+            writeln!(out, "  b{} -> b{}{};", k, e.to, style).unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
@@ -0,0 +1,463 @@
+// Ahead-of-time compiler from bytecode to a standalone C file
+// The generated program keeps the interpreter's machine: a stack and heap of tagged values,
+// Vsize headers in front of heap objects, frames found through fp, and saved frame pointers
+// and return addresses on a control stack. Every instruction gets a label, and anything that jumps to a
+// computed location (Call, Ret, Branch, a handler) goes back through a switch on pc. The heap the
+// program starts with, its heap section and constant objects, is pushed before the first instruction.
+
+use std::fmt::Write;
+
+use super::{Instr, Val, Binop, Unop};
+
+const RUNTIME: &str = r#"#include <stdio.h>
+#include <stdlib.h>
+#include <stdint.h>
+#include <setjmp.h>
+
+enum { VUNIT, VI32, VBOOL, VLOC, VUNDEF, VSIZE, VADDR };
+typedef struct { int tag; int64_t v; } Val;
+typedef struct { uint32_t fp; uint32_t ret; int called; } Frame;
+typedef struct { uint32_t target; uint32_t fp; size_t sp; size_t nframes; } Handler;
+
+static Val *stack; static size_t sp, stack_cap;
+static Val *heap; static size_t hp, heap_cap;
+static Frame *frames; static size_t nframes, frames_cap;
+static Handler *handlers; static size_t nhandlers, handlers_cap;
+static uint32_t pc, fp;
+static jmp_buf on_error;
+static Val error_val; static const char *error_msg;
+
+static Val mk(int tag, int64_t v) { Val r; r.tag = tag; r.v = v; return r; }
+
+static void print_val(FILE *out, Val v) { /* same text as the interpreter's {:?} */
+    switch (v.tag) {
+    case VUNIT: fprintf(out, "Vunit"); break;
+    case VI32: fprintf(out, "Vi32(%d)", (int32_t)v.v); break;
+    case VBOOL: fprintf(out, "Vbool(%s)", v.v ? "true" : "false"); break;
+    case VLOC: fprintf(out, "Vloc(%u)", (uint32_t)v.v); break;
+    case VUNDEF: fprintf(out, "Vundef"); break;
+    case VSIZE: fprintf(out, "Vsize(%d)", (int32_t)v.v); break;
+    default: fprintf(out, "Vaddr(%llu)", (unsigned long long)v.v); break;
+    }
+}
+
+static void die(const char *msg) { /* the interpreter panics here, nothing can catch it */
+    fprintf(stderr, "%s\n", msg);
+    exit(101);
+}
+
+static void fail(int code, const char *msg) { /* a VmError, thrown as its error code */
+    error_val = mk(VI32, code);
+    error_msg = msg;
+    longjmp(on_error, 1);
+}
+
+static void throw_val(Val v) {
+    error_val = v;
+    error_msg = 0;
+    longjmp(on_error, 1);
+}
+
+static void push(Val v) {
+    if (sp == stack_cap) {
+        stack_cap = stack_cap ? stack_cap * 2 : 1024;
+        stack = realloc(stack, stack_cap * sizeof(Val));
+        if (!stack) die("out of memory");
+    }
+    stack[sp++] = v;
+}
+
+static void push_checked(Val v) {
+    if (sp > 1024) fail(1, "Stack size exceeded");
+    push(v);
+}
+
+static Val pop(const char *msg) {
+    if (sp == 0) fail(2, msg);
+    return stack[--sp];
+}
+
+static void heap_push(Val v) {
+    if (hp == heap_cap) {
+        heap_cap = heap_cap ? heap_cap * 2 : 1024;
+        heap = realloc(heap, heap_cap * sizeof(Val));
+        if (!heap) die("out of memory");
+    }
+    heap[hp++] = v;
+}
+
+static int32_t wrap_add(int32_t a, int32_t b) { return (int32_t)((uint32_t)a + (uint32_t)b); }
+static int32_t wrap_sub(int32_t a, int32_t b) { return (int32_t)((uint32_t)a - (uint32_t)b); }
+static int32_t wrap_mul(int32_t a, int32_t b) { return (int32_t)((uint32_t)a * (uint32_t)b); }
+
+static void binary(int op) {
+    Val e1 = pop("No top value to pop -- Binary");
+    Val e2 = pop("No secondary value to pop -- Binary");
+    int32_t v1, v2;
+    if (op == 5 && (e1.tag == VUNIT || e2.tag == VUNIT)) { push(mk(VBOOL, e1.tag == e2.tag)); return; }
+    if (e1.tag != VI32 || e2.tag != VI32) fail(3, "Expected Vi32 -- Binary");
+    v1 = (int32_t)e1.v; v2 = (int32_t)e2.v;
+    switch (op) {
+    case 0: push(mk(VI32, wrap_add(v1, v2))); break;
+    case 1: push(mk(VI32, wrap_mul(v1, v2))); break;
+    case 2: push(mk(VI32, wrap_sub(v1, v2))); break;
+    case 3:
+        if (v2 == 0) fail(4, "Cannot divide by zero -- Binary Div");
+        if (v1 == INT32_MIN && v2 == -1) fail(13, "Division overflows an i32 -- Binary Div");
+        push(mk(VI32, v1 / v2)); break;
+    case 5: push(mk(VBOOL, v1 == v2)); break;
+    default: push(mk(VBOOL, v1 < v2)); break;
+    }
+}
+
+static void unary(void) {
+    Val v = pop("No value to pop");
+    if (v.tag != VBOOL) fail(3, "cant apply unary to non-bool");
+    push(mk(VBOOL, !v.v));
+}
+
+static void alloc(void) {
+    Val init = pop("No value to pop");
+    Val size = pop("No secondary value to pop");
+    int32_t x, counter = 0;
+    if (size.tag != VI32) fail(3, "Expected Vi32");
+    x = (int32_t)size.v;
+    heap_push(mk(VSIZE, x));
+    while (counter != x) {
+        if (hp > 1024) fail(7, "Heap is out of bounds");
+        heap_push(init);
+        counter++;
+    }
+    push(mk(VADDR, (int64_t)(hp - (size_t)(int64_t)x - 1)));
+}
+
+static void set(void) {
+    Val v = pop("Nothing to be popped in Set function");
+    Val idx = pop("Can't pop a second value in Set function");
+    Val addr = pop("Can't pop a third value from Set function");
+    int64_t i;
+    if (idx.tag != VI32) fail(3, "Can't index a non i32 in Set function");
+    if (addr.tag != VADDR) fail(3, "Invalid address location inside Set function");
+    i = (int64_t)(int32_t)idx.v + (int64_t)addr.v + 1;
+    if ((int32_t)idx.v < 0 || i >= (int64_t)hp) fail(5, "Heap index out of bounds");
+    heap[i] = v;
+}
+
+static void get(void) {
+    Val idx = pop("Nothing to pop -- Get function");
+    Val addr = pop("No secondary value to pop -- Get function");
+    int64_t i;
+    if (idx.tag != VI32) fail(3, "Get requires an i32 at top of stack");
+    if (addr.tag != VADDR) fail(3, "Get requires a Vaddr as secondary stack location");
+    i = (int64_t)(int32_t)idx.v + (int64_t)addr.v + 1;
+    if ((int32_t)idx.v < 0 || i >= (int64_t)hp) fail(5, "Heap index out of bounds");
+    push(heap[i]);
+}
+
+static void var(uint32_t x) {
+    size_t i = (size_t)fp + x;
+    if (i >= sp) fail(6, "Var index out of bound");
+    push(stack[i]);
+}
+
+static void store(uint32_t x) {
+    Val v = pop("No value to pop -- Store function");
+    size_t i = (size_t)fp + x;
+    if (i >= sp) fail(6, "Store is going out of bounds");
+    stack[i] = v;
+}
+
+static void peek(uint32_t x) {
+    if (x >= sp) fail(6, "Peek index out of bound");
+    push(stack[x]);
+}
+
+static void swap(void) {
+    Val top = pop("No value to pop");
+    Val second = pop("No secondary value to pop");
+    push(top);
+    push(second);
+}
+
+static void push_frame(uint32_t saved_fp, uint32_t ret, int called) {
+    if (nframes == frames_cap) {
+        frames_cap = frames_cap ? frames_cap * 2 : 64;
+        frames = realloc(frames, frames_cap * sizeof(Frame));
+        if (!frames) die("out of memory");
+    }
+    frames[nframes].fp = saved_fp;
+    frames[nframes].ret = ret;
+    frames[nframes].called = called;
+    nframes++;
+}
+
+static void set_frame(uint32_t x) {
+    if (x > sp) fail(6, "SetFrame passes more values than the stack holds");
+    push(mk(VLOC, fp)); /* the slot old programs number their locals after */
+    push_frame(fp, 0, 0);
+    fp = (uint32_t)(sp - x - 1);
+}
+
+static void call(void) {
+    Val target = pop("No value to pop -- Call");
+    if (target.tag != VLOC) fail(3, "Illegal use of call function");
+    push(mk(VLOC, pc));
+    if (nframes > 0 && !frames[nframes - 1].called) {
+        frames[nframes - 1].ret = pc;
+        frames[nframes - 1].called = 1;
+    } else {
+        push_frame(fp, pc, 1);
+    }
+    pc = (uint32_t)target.v;
+}
+
+static void ret(void) {
+    Val ret_val = pop("No value to pop -- Ret function");
+    if (nframes == 0) fail(2, "No frame to return from -- Ret function");
+    Frame f = frames[--nframes];
+    if (!f.called) fail(3, "Ret from a frame that was never called");
+    if (sp > fp) sp = fp;
+    fp = f.fp;
+    pc = f.ret;
+    push(ret_val);
+}
+
+static void branch(void) {
+    Val target = pop("Nothing to pop off stack -- Branch");
+    Val cond = pop("No secondary value to pop off the stack -- Branch");
+    if (target.tag != VLOC) fail(3, "Top value on stack must be a vloc -- Branch");
+    if (cond.tag != VBOOL) fail(3, "Secondary value must be of type Vbool -- Branch");
+    if (cond.v) pc = (uint32_t)target.v;
+}
+
+static int is_space(int c) { return c == ' ' || c == '\t' || c == '\n' || c == '\f' || c == '\r'; }
+
+static void read_char(void) {
+    int c;
+    if (sp > 1024) fail(1, "Stack size exceeded");
+    c = getchar();
+    push(c == EOF ? mk(VUNIT, 0) : mk(VI32, c));
+}
+
+static void print_byte(void) {
+    Val c = pop("No value to pop -- Print");
+    if (c.tag != VI32 || (int32_t)c.v < 0 || (int32_t)c.v > 255) fail(3, "Expected a byte -- Print");
+    putchar((int)c.v);
+}
+
+static void read_int(void) {
+    int c, neg = 0, digits = 0;
+    int64_t n = 0;
+    if (sp > 1024) fail(1, "Stack size exceeded");
+    do { c = getchar(); } while (c != EOF && is_space(c));
+    if (c == EOF) { push(mk(VUNIT, 0)); return; }
+    if (c == '-' || c == '+') { neg = c == '-'; c = getchar(); }
+    while (c != EOF && c >= '0' && c <= '9') {
+        if (n <= 2147483648LL) n = n * 10 + (c - '0');
+        digits++;
+        c = getchar();
+    }
+    if (c != EOF) ungetc(c, stdin);
+    if (neg) n = -n;
+    if (!digits || n > INT32_MAX || n < INT32_MIN) fail(8, "Input is not a valid i32 -- ReadInt");
+    push(mk(VI32, n));
+}
+
+static void push_handler(uint32_t target) {
+    if (nhandlers == handlers_cap) {
+        handlers_cap = handlers_cap ? handlers_cap * 2 : 16;
+        handlers = realloc(handlers, handlers_cap * sizeof(Handler));
+        if (!handlers) die("out of memory");
+    }
+    handlers[nhandlers].target = target;
+    handlers[nhandlers].fp = fp;
+    handlers[nhandlers].sp = sp;
+    handlers[nhandlers].nframes = nframes;
+    nhandlers++;
+}
+
+static void pop_handler(void) {
+    if (nhandlers == 0) fail(9, "No handler to pop -- PopHandler");
+    nhandlers--;
+}
+
+static size_t object(Val addr, int32_t *len, const char *who) { /* base and Vsize of the object at addr */
+    if (addr.tag != VADDR) fail(3, who);
+    if ((size_t)addr.v >= hp) fail(5, "Heap index out of bounds");
+    if (heap[addr.v].tag != VSIZE) fail(3, who);
+    *len = (int32_t)heap[addr.v].v;
+    return (size_t)addr.v;
+}
+
+static void range(size_t base, int32_t len, Val start, Val count, const char *who) {
+    int64_t end;
+    if (start.tag != VI32 || count.tag != VI32) fail(3, who);
+    end = (int64_t)(int32_t)start.v + (int32_t)count.v; /* can't wrap around like an int32_t sum */
+    if ((int32_t)start.v < 0 || (int32_t)count.v < 0 || end > len || (int64_t)base + 1 + end > (int64_t)hp)
+        fail(5, "Heap index out of bounds");
+}
+
+static void len(void) {
+    Val addr = pop("Nothing to pop -- Len");
+    int32_t n;
+    object(addr, &n, "Len requires a Vaddr at top of stack");
+    push(mk(VI32, n));
+}
+
+static void array_copy(void) {
+    Val count = pop("Nothing to pop -- ArrayCopy");
+    Val dst_idx = pop("No secondary value to pop -- ArrayCopy");
+    Val dst = pop("No tertiary value to pop -- ArrayCopy");
+    Val src_idx = pop("No fourth value to pop -- ArrayCopy");
+    Val src = pop("No fifth value to pop -- ArrayCopy");
+    int32_t src_len, dst_len, k;
+    size_t from = object(src, &src_len, "ArrayCopy requires a Vaddr source");
+    size_t to = object(dst, &dst_len, "ArrayCopy requires a Vaddr destination");
+    range(from, src_len, src_idx, count, "ArrayCopy requires i32 indices and count");
+    range(to, dst_len, dst_idx, count, "ArrayCopy requires i32 indices and count");
+    from += 1 + (size_t)src_idx.v;
+    to += 1 + (size_t)dst_idx.v;
+    if (to <= from) for (k = 0; k < (int32_t)count.v; k++) heap[to + k] = heap[from + k];
+    else for (k = (int32_t)count.v - 1; k >= 0; k--) heap[to + k] = heap[from + k];
+}
+
+static void array_fill(void) {
+    Val v = pop("Nothing to pop -- ArrayFill");
+    Val count = pop("No secondary value to pop -- ArrayFill");
+    Val start = pop("No tertiary value to pop -- ArrayFill");
+    Val addr = pop("No fourth value to pop -- ArrayFill");
+    int32_t n, k;
+    size_t base = object(addr, &n, "ArrayFill requires a Vaddr");
+    range(base, n, start, count, "ArrayFill requires an i32 index and count");
+    for (k = 0; k < (int32_t)count.v; k++) heap[base + 1 + start.v + k] = v;
+}
+
+static int32_t native_i32(Val v) {
+    if (v.tag != VI32) fail(3, "Native function expected a Vi32");
+    return (int32_t)v.v;
+}
+
+static Val *native_args(size_t arity) {
+    if (sp < arity) fail(2, "Not enough arguments -- CallNative");
+    sp -= arity;
+    return stack + sp;
+}
+
+static void native_log(void) { Val *a = native_args(1); print_val(stderr, a[0]); fprintf(stderr, "\n"); push_checked(mk(VUNIT, 0)); }
+static void native_abs(void) { Val *a = native_args(1); int32_t x = native_i32(a[0]); push_checked(mk(VI32, x < 0 ? wrap_sub(0, x) : x)); }
+static void native_min(void) { Val *a = native_args(2); int32_t x = native_i32(a[0]), y = native_i32(a[1]); push_checked(mk(VI32, x < y ? x : y)); }
+static void native_max(void) { Val *a = native_args(2); int32_t x = native_i32(a[0]), y = native_i32(a[1]); push_checked(mk(VI32, x > y ? x : y)); }
+
+static void throw(void) {
+    throw_val(pop("No value to pop -- Throw"));
+}
+
+static void out_of_bounds(uint32_t at) {
+    fprintf(stderr, "exec: pc %u out of bounds\n", at);
+    exit(101);
+}
+
+"#;
+
+fn val(v: &Val) -> String { // function to spell a constant the way the runtime builds values
+    match v {
+        Val::Vunit => "mk(VUNIT, 0)".to_string(),
+        Val::Vi32(i) => format!("mk(VI32, {})", i),
+        Val::Vbool(b) => format!("mk(VBOOL, {})", *b as i32),
+        Val::Vloc(l) => format!("mk(VLOC, {}u)", l),
+        Val::Vundef => "mk(VUNDEF, 0)".to_string(),
+        Val::Vsize(i) => format!("mk(VSIZE, {})", i),
+        Val::Vaddr(a) => format!("mk(VADDR, {})", a)
+    }
+}
+
+fn binop(b: &Binop) -> u32 {
+    match b {
+        Binop::Add => 0,
+        Binop::Mul => 1,
+        Binop::Sub => 2,
+        Binop::Div => 3,
+        Binop::Lt => 4,
+        Binop::Eq => 5
+    }
+}
+
+pub fn compile_c(program: &[Instr], heap: &[Val], natives: &[String]) -> Result<String, String> { // function to translate program into C, starting from heap, natives[i] names the host function CallNative(i) calls
+    let mut out = String::from(RUNTIME);
+    let mut body = String::new();
+    for (pc, i) in program.iter().enumerate() {
+        let next = pc + 1;
+        let code = match i {
+            Instr::Push(v) => format!("push_checked({});", val(v)),
+            Instr::Pop => "pop(\"Nothing to pop\");".to_string(),
+            Instr::Peek(x) => format!("peek({}u);", x),
+            Instr::Unary(Unop::Neg) => "unary();".to_string(),
+            Instr::Binary(b) => format!("binary({});", binop(b)),
+            Instr::Swap => "swap();".to_string(),
+            Instr::Alloc => "alloc();".to_string(),
+            Instr::Set => "set();".to_string(),
+            Instr::Get => "get();".to_string(),
+            Instr::Var(x) => format!("var({}u);", x),
+            Instr::Store(x) => format!("store({}u);", x),
+            Instr::SetFrame(x) => format!("set_frame({}u);", x),
+            Instr::Call => "call(); goto dispatch;".to_string(),
+            Instr::Ret => "ret(); goto dispatch;".to_string(),
+            Instr::Branch => "branch(); goto dispatch;".to_string(),
+            Instr::Halt => "goto done;".to_string(),
+            Instr::ReadChar => "read_char();".to_string(),
+            Instr::ReadInt => "read_int();".to_string(),
+            Instr::Print => "print_byte();".to_string(),
+            Instr::PushHandler(x) => format!("push_handler({}u);", x),
+            Instr::PopHandler => "pop_handler();".to_string(),
+            Instr::Throw => "throw();".to_string(),
+            Instr::Len => "len();".to_string(),
+            Instr::ArrayCopy => "array_copy();".to_string(),
+            Instr::ArrayFill => "array_fill();".to_string(),
+            Instr::AddImm(c) => format!("push_checked(mk(VI32, {})); binary(0);", c),
+            Instr::VarVarBinary(x, y, b) => format!("var({}u); var({}u); binary({});", x, y, binop(b)),
+            Instr::Jump(x) => format!("if (sp > 1023) fail(1, \"Stack size exceeded\"); pc = {}u; goto dispatch;", x),
+            Instr::CallNative(x) => {
+                match natives.get(*x as usize).map(|n| n.as_str()) {
+                    Some("log") | Some("abs") | Some("min") | Some("max") => format!("native_{}();", natives[*x as usize]),
+                    Some(name) => return Err(format!("compile-c has no C version of native {}", name)),
+                    None => return Err(format!("CallNative({}) at {} has no native function", x, pc))
+                }
+            }
+            Instr::PushConst(x) => return Err(format!("PushConst({}) at {} was never loaded, Program::load_data turns it into a Push", x, pc))
+        };
+        writeln!(body, "L{}: pc = {}u; {}", pc, next, code).unwrap();
+    }
+
+    out.push_str("int main(void) {\n");
+    for v in heap.iter() {
+        writeln!(out, "    heap_push({});", val(v)).unwrap();
+    }
+    out.push_str("    if (setjmp(on_error)) { /* send the error to the innermost handler, like unwind */\n");
+    out.push_str("        if (nhandlers == 0) {\n");
+    out.push_str("            if (error_msg) fprintf(stderr, \"%s\\n\", error_msg);\n");
+    out.push_str("            else { fprintf(stderr, \"Uncaught exception: \"); print_val(stderr, error_val); fprintf(stderr, \"\\n\"); }\n");
+    out.push_str("            exit(101);\n");
+    out.push_str("        }\n");
+    out.push_str("        nhandlers--;\n");
+    out.push_str("        if (sp > handlers[nhandlers].sp) sp = handlers[nhandlers].sp;\n");
+    out.push_str("        nframes = handlers[nhandlers].nframes;\n");
+    out.push_str("        fp = handlers[nhandlers].fp;\n");
+    out.push_str("        push(error_val);\n");
+    out.push_str("        pc = handlers[nhandlers].target;\n");
+    out.push_str("    }\n");
+    out.push_str("dispatch:\n");
+    out.push_str("    switch (pc) {\n");
+    for pc in 0..program.len() {
+        writeln!(out, "    case {}: goto L{};", pc, pc).unwrap();
+    }
+    out.push_str("    default: out_of_bounds(pc);\n");
+    out.push_str("    }\n");
+    out.push_str(&body);
+    writeln!(out, "    out_of_bounds({}u);", program.len()).unwrap();
+    out.push_str("done:\n");
+    out.push_str("    if (sp == 0) die(\"called `Option::unwrap()` on a `None` value\");\n");
+    out.push_str("    print_val(stdout, stack[sp - 1]);\n");
+    out.push_str("    return 0;\n");
+    out.push_str("}\n");
+    Ok(out)
+}
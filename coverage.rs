@@ -0,0 +1,179 @@
+// Bytecode coverage for `vm file.o --coverage[=file.info]`
+// Runs the program in the interpreter, counting how often each pc executes and, for every
+// Branch, how often it jumped (taken) and how often it fell through (not taken). The counts are
+// written as an lcov tracefile (file.info next to file.o by default) that genhtml and the other
+// lcov tools read. With debug info each pc counts towards the source line it came from, a line
+// running as often as its busiest instruction. Without it each pc maps to its own line of a
+// disassembly listing, written to file.lst beside the object, which the tracefile names as its
+// source. If the tracefile already exists the new counts are added to it, so one file can gather
+// the coverage of many runs; records for other sources in it are kept as they are.
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+
+use super::{State, Program, Instr, fetch, step};
+use debuginfo::disassemble;
+
+#[derive(Debug,Clone,Default)]
+pub struct SourceCoverage {
+    pub lines: BTreeMap<u32, u64>,                       //Executions of each line
+    pub branches: BTreeMap<(u32, u32, u32), Option<u64>> //(line, block, branch) to times taken, None if the block never ran
+}
+
+pub type Tracefile = BTreeMap<String, SourceCoverage>; //Coverage by source file name
+
+pub struct Coverage {
+    pub counts: Vec<u64>,  //How many times each pc executed
+    pub taken: Vec<u64>,   //How many times the Branch at each pc jumped
+    pub fallen: Vec<u64>   //How many times the Branch at each pc fell through
+}
+
+impl Coverage {
+    pub fn new(program: &[Instr]) -> Coverage {
+        Coverage{counts: vec![0; program.len()], taken: vec![0; program.len()], fallen: vec![0; program.len()]}
+    }
+
+    fn note(&mut self, pc: u32, jumps: Option<bool>) { // function to record one executed instruction
+        self.counts[pc as usize] += 1;
+        match jumps {
+            Some(true) => self.taken[pc as usize] += 1,
+            Some(false) => self.fallen[pc as usize] += 1,
+            None => ()
+        }
+    }
+}
+
+pub fn exec_coverage(program: &Program, s: &mut State, tracefile: &str, listing: &str) { // function to run the program like exec does, then merge its coverage into tracefile
+    let mut c = Coverage::new(&program.code);
+    'mainloop: loop{
+        if s.halt { break 'mainloop }
+        let pc = s.pc;
+        let i = match fetch(&program.code, s){
+            Ok(i) => i,
+            Err(e) => panic!("exec: {}", e)
+        };
+        let jumps = match *i { // the Branch pops its target, then the Vbool deciding whether to go there
+            Instr::Branch if s.stack.len() >= 2 => s.stack[s.stack.len() - 2].as_bool(),
+            _ => None
+        };
+        if let Err(e) = step(i, s) {
+            panic!("{}", e);
+        }
+        c.note(pc, jumps);
+    }
+
+    let result = s.stack.pop().unwrap();
+    print!("{:?}",result);
+
+    if program.debug.is_none() {
+        fs::write(listing, disassemble(program)).expect("Could not write the listing");
+    }
+    let mut merged = match fs::read_to_string(tracefile) {
+        Ok(text) => parse_lcov(&text),
+        Err(_) => Tracefile::new()
+    };
+    merge(&mut merged, &to_tracefile(&c, program, listing));
+    fs::write(tracefile, to_lcov(&merged)).expect("Could not write the coverage");
+}
+
+pub fn to_tracefile(c: &Coverage, program: &Program, listing: &str) -> Tracefile { // function to put the counts on source lines, or on the lines of the listing
+    let mut t = Tracefile::new();
+    for (pc, i) in program.code.iter().enumerate() {
+        let (name, line) = match program.debug {
+            Some(ref d) => match d.line_at(pc as u32) {
+                Some((file, line, _)) => (file.to_string(), line),
+                None => continue // code with no source behind it
+            },
+            None => (listing.to_string(), pc as u32 + 1)
+        };
+        let source = t.entry(name).or_default();
+        let n = source.lines.entry(line).or_insert(0);
+        *n = max(*n, c.counts[pc]);
+        if let Instr::Branch = *i {
+            let ran = c.counts[pc] > 0;
+            source.branches.insert((line, pc as u32, 0), if ran { Some(c.taken[pc]) } else { None });
+            source.branches.insert((line, pc as u32, 1), if ran { Some(c.fallen[pc]) } else { None });
+        }
+    }
+    t
+}
+
+pub fn merge(into: &mut Tracefile, from: &Tracefile) { // function to add the counts of from to into
+    for (name, source) in from.iter() {
+        let target = into.entry(name.clone()).or_default();
+        for (line, n) in source.lines.iter() {
+            *target.lines.entry(*line).or_insert(0) += *n;
+        }
+        for (key, n) in source.branches.iter() {
+            add_branch(target, *key, *n);
+        }
+    }
+}
+
+fn add_branch(source: &mut SourceCoverage, key: (u32, u32, u32), n: Option<u64>) { // function to add to a branch count, a block that ran beats one that never did
+    let sum = match (source.branches.get(&key).cloned().unwrap_or(None), n) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b)
+    };
+    source.branches.insert(key, sum);
+}
+
+pub fn parse_lcov(text: &str) -> Tracefile { // function to read the SF, DA and BRDA records of an lcov tracefile, skipping the rest
+    let mut t = Tracefile::new();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("SF:") {
+            let name = name.to_string();
+            t.entry(name.clone()).or_default();
+            current = Some(name);
+        }
+        else if line == "end_of_record" {
+            current = None;
+        }
+        else if let Some(ref name) = current {
+            let source = t.get_mut(name).unwrap();
+            if let Some(rest) = line.strip_prefix("DA:") {
+                let fields: Vec<&str> = rest.split(',').collect();
+                if let (Some(l), Some(n)) = (fields.first().and_then(|f| f.parse::<u32>().ok()), fields.get(1).and_then(|f| f.parse::<u64>().ok())) {
+                    *source.lines.entry(l).or_insert(0) += n;
+                }
+            }
+            else if let Some(rest) = line.strip_prefix("BRDA:") {
+                let fields: Vec<&str> = rest.split(',').collect();
+                if fields.len() == 4 {
+                    if let (Ok(l), Ok(block), Ok(branch)) = (fields[0].parse::<u32>(), fields[1].parse::<u32>(), fields[2].parse::<u32>()) {
+                        let n = fields[3].parse::<u64>().ok(); // "-" when the block never ran
+                        add_branch(source, (l, block, branch), n);
+                    }
+                }
+            }
+        }
+    }
+    t
+}
+
+pub fn to_lcov(t: &Tracefile) -> String { // function to write the tracefile out in lcov format
+    let mut out = String::new();
+    for (name, source) in t.iter() {
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", name).unwrap();
+        for (&(line, block, branch), n) in source.branches.iter() {
+            match *n {
+                Some(n) => writeln!(out, "BRDA:{},{},{},{}", line, block, branch, n).unwrap(),
+                None => writeln!(out, "BRDA:{},{},{},-", line, block, branch).unwrap()
+            }
+        }
+        writeln!(out, "BRF:{}", source.branches.len()).unwrap();
+        writeln!(out, "BRH:{}", source.branches.values().filter(|n| n.unwrap_or(0) > 0).count()).unwrap();
+        for (line, n) in source.lines.iter() {
+            writeln!(out, "DA:{},{}", line, n).unwrap();
+        }
+        writeln!(out, "LF:{}", source.lines.len()).unwrap();
+        writeln!(out, "LH:{}", source.lines.values().filter(|n| **n > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+    }
+    out
+}
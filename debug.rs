@@ -0,0 +1,378 @@
+// Time-travel debugger for `vm file.o --debug[=commands.txt]`
+// Every step the program takes is recorded as a Delta: the pc and fp before and after it, the
+// part of the stack, control stack and handler stack it replaced, and the heap cells it wrote.
+// Undoing a delta puts the machine back the way it was before the step. Redoing it replays the
+// step without running the instruction again, so going forward over recorded history never reads
+// input twice and always arrives at the same state. New instructions are only executed (and
+// recorded) once the debugger goes past the end of the history. Commands come from the given
+// file, or from stdin, and the debugger writes to stdout.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Write};
+
+use super::{State, Program, Instr, Frame, Handler, Word, fetch, step};
+use debuginfo::describe;
+use isa::{self, Effect};
+
+#[derive(Debug,Clone)]
+pub struct Splice<T> {
+    pub at: usize,       //Where the two versions first differ
+    pub removed: Vec<T>, //What was there from at onwards before the step
+    pub added: Vec<T>    //What is there from at onwards after it
+}
+
+impl<T: Clone + PartialEq> Splice<T> {
+    fn between(low: usize, before: &[T], after: &[T], written: Option<usize>) -> Splice<T> { // function to find where after stops matching before, no later than the slot written, before holding only what was there from low onwards
+        debug_assert!(after.len() >= low, "the step changed a slot below the one reach gave");
+        let after = &after[low..];
+        let written = written.map(|w| w - low);
+        let mut at = 0;
+        while at < before.len() && at < after.len() && before[at] == after[at] {
+            at += 1;
+        }
+        if let Some(w) = written {
+            if w < at { // a write of the value that was already there still counts as a write
+                at = w;
+            }
+        }
+        Splice{at: low + at, removed: before[at..].to_vec(), added: after[at..].to_vec()}
+    }
+
+    fn undo(&self, v: &mut Vec<T>) {
+        v.truncate(self.at);
+        v.extend_from_slice(&self.removed);
+    }
+
+    fn redo(&self, v: &mut Vec<T>) {
+        v.truncate(self.at);
+        v.extend_from_slice(&self.added);
+    }
+
+    fn wrote(&self, k: usize) -> Option<(Option<&T>, &T)> { // what slot k held before and after the step, if the step wrote it
+        if k >= self.at && k < self.at + self.added.len() {
+            Some((self.removed.get(k - self.at), &self.added[k - self.at]))
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Delta {
+    pub pc: u32,                   //Where the step started
+    pub fp: u32,
+    pub next_pc: u32,              //Where it left the machine
+    pub next_fp: u32,
+    pub halted: bool,              //Did the step halt the machine?
+    pub stack: Splice<Word>,
+    pub control: Splice<Frame>,
+    pub handlers: Splice<Handler>,
+    pub heap_len: usize,           //Heap size before the step
+    pub next_heap_len: usize,      //and after it
+    pub heap: Vec<(usize, Option<Word>, Word)>, //Cells written: where, what was there (None if the step allocated it), what is there now
+    pub error: Option<String>      //The error the step stopped the program with, if nothing handled it
+}
+
+fn writes_heap(i: &Instr) -> bool { // only these can change the heap, the rest leave it alone
+    matches!(*i, Instr::Alloc | Instr::Set | Instr::ArrayCopy | Instr::ArrayFill | Instr::CallNative(_))
+}
+
+fn written(i: &Instr, s: &State) -> (Option<usize>, Option<usize>) { // the stack slot a Store writes and the heap cell a Set writes, worked out before the step
+    match *i {
+        Instr::Store(x) => (Some(s.fp as usize + x as usize), None),
+        Instr::Set if s.stack.len() >= 3 => {
+            let n = s.stack.len();
+            match (s.stack[n - 3].as_addr(), s.stack[n - 2].as_i32()) {
+                (Some(addr), Some(idx)) if idx >= 0 => (None, Some(addr + idx as usize + 1)),
+                _ => (None, None)
+            }
+        }
+        _ => (None, None)
+    }
+}
+
+fn reach(i: &Instr, s: &State, slot: Option<usize>) -> (usize, usize, usize) { // the lowest stack, control stack and handler stack positions the step can change
+    let (sp, frames) = match s.handlers.last() { // an error goes back to the innermost handler
+        Some(h) => (h.sp, h.frames),
+        None => (s.stack.len(), s.control.len())
+    };
+    let pops = match isa::opcode(i).effect {
+        Effect::Fixed(pops, _) => s.stack.len().saturating_sub(pops as usize),
+        Effect::Return => s.stack.len().saturating_sub(1).min(s.fp as usize),
+        Effect::Native => return (0, 0, 0) // a native gets the whole machine
+    };
+    let low = slot.map_or(pops, |w| w.min(pops)).min(sp).min(s.stack.len());
+    (low, frames.min(s.control.len().saturating_sub(1)), s.handlers.len().saturating_sub(1))
+}
+
+pub fn record(program: &Program, s: &mut State) -> Delta { // function to run one instruction and return what it did
+    let (pc, fp) = (s.pc, s.fp);
+    let heap_len = s.heap.len();
+    let mut slot = None;
+    let mut cell = None;
+    let mut heap_before = None;
+    let mut low = (s.stack.len(), s.control.len(), s.handlers.len());
+    let (mut stack, mut control, mut handlers) = (Vec::new(), Vec::new(), Vec::new());
+    let error = match fetch(&program.code, s){
+        Ok(i) => {
+            let w = written(i, s);
+            slot = w.0;
+            cell = w.1;
+            low = reach(i, s, slot);
+            stack = s.stack[low.0..].to_vec(); // only what the step can change is kept
+            control = s.control[low.1..].to_vec();
+            handlers = s.handlers[low.2..].to_vec();
+            if writes_heap(i) {
+                heap_before = Some(s.heap.clone());
+            }
+            match step(i, s){
+                Ok(()) => None,
+                Err(e) => Some(format!("{}", e))
+            }
+        }
+        Err(e) => Some(format!("exec: {}", e))
+    };
+    let mut heap = Vec::new();
+    if let Some(before) = heap_before {
+        for k in 0..s.heap.len() {
+            let old = before.get(k).cloned();
+            if old != Some(s.heap[k]) || cell == Some(k) {
+                heap.push((k, old, s.heap[k]));
+            }
+        }
+    }
+    Delta{pc, fp, next_pc: s.pc, next_fp: s.fp, halted: s.halt,
+          stack: Splice::between(low.0, &stack, &s.stack, slot),
+          control: Splice::between(low.1, &control, &s.control, None),
+          handlers: Splice::between(low.2, &handlers, &s.handlers, None),
+          heap_len, next_heap_len: s.heap.len(), heap, error}
+}
+
+pub fn undo(s: &mut State, d: &Delta) { // function to put the machine back to how it was before the step
+    s.pc = d.pc;
+    s.fp = d.fp;
+    s.halt = false;
+    d.stack.undo(&mut s.stack);
+    d.control.undo(&mut s.control);
+    d.handlers.undo(&mut s.handlers);
+    for &(k, old, _) in d.heap.iter() {
+        if let Some(old) = old {
+            s.heap[k] = old;
+        }
+    }
+    s.heap.truncate(d.heap_len);
+}
+
+pub fn redo(s: &mut State, d: &Delta) { // function to replay the step from the record, without running the instruction
+    s.pc = d.next_pc;
+    s.fp = d.next_fp;
+    s.halt = d.halted;
+    d.stack.redo(&mut s.stack);
+    d.control.redo(&mut s.control);
+    d.handlers.redo(&mut s.handlers);
+    s.heap.resize(d.next_heap_len, Word::UNDEF);
+    for &(k, _, new) in d.heap.iter() {
+        s.heap[k] = new;
+    }
+}
+
+pub enum Location {
+    Slot(usize), //An absolute stack slot
+    Cell(usize)  //A heap cell
+}
+
+pub struct Debugger<'a> {
+    program: &'a Program,
+    history: Vec<Delta>,         //Every step recorded so far, oldest first
+    at: usize,                   //How many of them are applied to the machine
+    breakpoints: BTreeSet<u32>
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a Program) -> Debugger<'a> {
+        Debugger{program, history: Vec::new(), at: 0, breakpoints: BTreeSet::new()}
+    }
+
+    fn finished(&self, s: &State) -> bool { // at the end of the history, and the program can't go on
+        self.at == self.history.len() && (s.halt || self.history.last().is_some_and(|d| d.error.is_some()))
+    }
+
+    pub fn forward(&mut self, s: &mut State) -> bool { // function to take one step, replaying it if it is already recorded
+        if self.at < self.history.len() {
+            redo(s, &self.history[self.at]);
+        }
+        else if self.finished(s) {
+            return false;
+        }
+        else {
+            let d = record(self.program, s);
+            self.history.push(d);
+        }
+        self.at += 1;
+        true
+    }
+
+    pub fn backward(&mut self, s: &mut State) -> bool { // function to undo the last step
+        if self.at == 0 {
+            return false;
+        }
+        self.at -= 1;
+        undo(s, &self.history[self.at]);
+        true
+    }
+
+    pub fn last_write(&mut self, s: &mut State, l: &Location) -> Option<String> { // function to run back to just before the step that last wrote l, describing the write
+        let mut k = self.at;
+        while k > 0 {
+            k -= 1;
+            let d = &self.history[k];
+            let change = match *l {
+                Location::Slot(n) => d.stack.wrote(n).map(|(old, new)| (old.cloned(), *new)),
+                Location::Cell(n) => d.heap.iter().find(|w| w.0 == n).map(|w| (w.1, w.2))
+            };
+            if let Some((old, new)) = change {
+                let what = match old {
+                    Some(old) => format!("{:?} -> {:?}", old, new),
+                    None => format!("{:?} (allocated)", new)
+                };
+                while self.at > k {
+                    self.backward(s);
+                }
+                return Some(what);
+            }
+        }
+        None
+    }
+
+    fn show(&self, s: &State) { // function to print where the machine is
+        if self.finished(s) {
+            match self.history.last().and_then(|d| d.error.clone()) {
+                Some(e) => println!("#{} stopped: {}", self.at, e),
+                None => println!("#{} halted with {:?}", self.at, s.stack.last().cloned().unwrap_or(Word::UNIT))
+            }
+            return;
+        }
+        match self.program.code.get(s.pc as usize) {
+            Some(i) => println!("#{} {} fp {}: {}", self.at, describe(self.program, s.pc), s.fp, i),
+            None => println!("#{} pc {} fp {}: outside the program", self.at, s.pc, s.fp)
+        }
+    }
+
+    fn run(&mut self, s: &mut State, forwards: bool, limit: Option<usize>) { // function to step until a breakpoint, the end of the history either way, or limit steps
+        let mut n = 0;
+        while limit.is_none_or(|l| n < l) {
+            let moved = if forwards { self.forward(s) } else { self.backward(s) };
+            if !moved {
+                break;
+            }
+            n += 1;
+            if limit.is_none() && self.breakpoints.contains(&s.pc) {
+                break;
+            }
+        }
+    }
+
+    pub fn command(&mut self, s: &mut State, line: &str) -> bool { // function to carry out one command, false once the session is over
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |k: usize| words.get(k).and_then(|w| w.parse::<usize>().ok());
+        match words.first().cloned().unwrap_or("") {
+            "" => return true,
+            "step" | "s" => self.run(s, true, Some(number(1).unwrap_or(1))),
+            "back" | "b" => self.run(s, false, Some(number(1).unwrap_or(1))),
+            "continue" | "c" => self.run(s, true, None),
+            "reverse" | "rc" => self.run(s, false, None),
+            "goto" | "g" => {
+                let target = number(1).unwrap_or(0);
+                while self.at > target && self.backward(s) {}
+                while self.at < target && self.forward(s) {}
+            }
+            "break" | "delete" => {
+                match number(1) {
+                    Some(pc) if words[0] == "break" => { self.breakpoints.insert(pc as u32); },
+                    Some(pc) => { self.breakpoints.remove(&(pc as u32)); },
+                    None => println!("{} needs a pc", words[0])
+                }
+                return true;
+            }
+            "last-write" | "lw" => {
+                let named = words.get(2).and_then(|name| self.program.debug.as_ref()?.local_slot(s.pc, name));
+                let l = match (words.get(1).cloned(), number(2).or(named.map(|n| n as usize))) {
+                    (Some("stack"), Some(n)) => Location::Slot(n),
+                    (Some("var"), Some(n)) => Location::Slot(s.fp as usize + n),
+                    (Some("heap"), Some(n)) => Location::Cell(n),
+                    _ => {
+                        println!("last-write stack N, var N, var NAME or heap N");
+                        return true;
+                    }
+                };
+                match self.last_write(s, &l) {
+                    Some(what) => println!("written: {}", what),
+                    None => println!("not written since the program started")
+                }
+            }
+            "stack" => {
+                for (k, w) in s.stack.iter().enumerate() {
+                    let name = match self.program.debug {
+                        Some(ref d) if k >= s.fp as usize => d.local_name(s.pc, (k - s.fp as usize) as u32),
+                        _ => None
+                    };
+                    println!("{:>6}{} {:?}{}", k, if k == s.fp as usize { " fp" } else { "   " }, w,
+                             name.map_or(String::new(), |n| format!("  {}", n)));
+                }
+                return true;
+            }
+            "heap" => {
+                for (k, w) in s.heap.iter().enumerate() {
+                    println!("{:>6}    {:?}", k, w);
+                }
+                return true;
+            }
+            "frames" => {
+                for f in s.control.iter().rev() {
+                    println!("  fp {} ret {:?}", f.fp, f.ret);
+                }
+                return true;
+            }
+            "print" | "p" => (),
+            "quit" | "q" => return false,
+            _ => {
+                println!("commands: step [n], back [n], continue, reverse, goto STEP, break PC, delete PC,");
+                println!("          last-write stack|var|heap N, last-write var NAME, stack, heap, frames, print, quit");
+                return true;
+            }
+        }
+        self.show(s);
+        true
+    }
+}
+
+pub fn debug(program: &Program, s: &mut State, commands: Option<&str>) { // function to run a debugging session over the program, from a command file or stdin
+    let script: Option<Vec<String>> = commands.map(|path| {
+        fs::read_to_string(path).expect("Could not read the debugger commands").lines().map(|l| l.to_string()).collect()
+    });
+    let mut d = Debugger::new(program);
+    d.show(s);
+    let mut next = 0;
+    loop {
+        let line = match script {
+            Some(ref lines) => { // echo scripted commands so the transcript reads like a session
+                if next >= lines.len() { break }
+                next += 1;
+                println!("(vm) {}", lines[next - 1]);
+                lines[next - 1].clone()
+            }
+            None => { // a line at a time, so the program can read the rest of stdin
+                print!("(vm) ");
+                io::stdout().flush().unwrap();
+                let mut l = String::new();
+                if io::stdin().read_line(&mut l).unwrap_or(0) == 0 { break }
+                l
+            }
+        };
+        if !d.command(s, line.trim()) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,314 @@
+// Debug info: the optional debug section of an object file
+// Compilers targeting the vm can say which function each pc belongs to, where in the source it
+// came from, and what the frame slots of each function are called. The section holds four tables,
+// big endian like the rest of the object file, with names written the way read_name reads them:
+//   files      count:u32, then the source file names
+//   functions  count:u32, then name, start:u32, end:u32 for the pcs start..end
+//   lines      count:u32, then pc:u32 file:u32 line:u32 column:u32, sorted by pc, each one covering
+//              the pcs up to the next; a file of u32::MAX marks code with no source
+//   locals     count:u32, then function:u32 slot:u32 name, slot being the i of Var(i)/Store(i)
+// Everything that shows a pc (the disassembler, --trace, backtraces, the debugger, profiles and
+// coverage) asks this for a name or a source position, and falls back to the raw pc without it.
+
+use std::fmt::Write;
+use std::slice::Iter;
+
+use super::{Program, Instr, State, fromBinary, read_name, fetch, step};
+use object::ToBinary;
+
+pub const NO_FILE: u32 = u32::MAX;
+
+#[derive(Debug,Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub start: u32, //The first pc of the function
+    pub end: u32    //One past its last pc
+}
+
+#[derive(Debug,Clone)]
+pub struct LineEntry {
+    pub pc: u32,    //The first pc the entry covers
+    pub file: u32,  //Index into files, or NO_FILE
+    pub line: u32,
+    pub column: u32
+}
+
+#[derive(Debug,Clone)]
+pub struct LocalInfo {
+    pub function: u32, //Index into functions
+    pub slot: u32,     //The frame slot, fp + slot on the stack
+    pub name: String
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub functions: Vec<FunctionInfo>,
+    pub lines: Vec<LineEntry>,
+    pub locals: Vec<LocalInfo>
+}
+
+impl fromBinary for DebugInfo { // function to read the debug section's four tables
+    fn from_binary(bytes: &mut Iter<u8>) -> Self {
+        let count = <u32 as fromBinary>::from_binary(bytes);
+        let files = (0..count).map(|_| read_name(bytes)).collect();
+        let count = <u32 as fromBinary>::from_binary(bytes);
+        let mut functions = Vec::new();
+        for _ in 0..count {
+            let name = read_name(bytes);
+            let start = <u32 as fromBinary>::from_binary(bytes);
+            let end = <u32 as fromBinary>::from_binary(bytes);
+            functions.push(FunctionInfo{name, start, end});
+        }
+        let count = <u32 as fromBinary>::from_binary(bytes);
+        let mut lines: Vec<LineEntry> = Vec::new();
+        for _ in 0..count {
+            let pc = <u32 as fromBinary>::from_binary(bytes);
+            let file = <u32 as fromBinary>::from_binary(bytes);
+            let line = <u32 as fromBinary>::from_binary(bytes);
+            let column = <u32 as fromBinary>::from_binary(bytes);
+            lines.push(LineEntry{pc, file, line, column});
+        }
+        let count = <u32 as fromBinary>::from_binary(bytes);
+        let mut locals = Vec::new();
+        for _ in 0..count {
+            let function = <u32 as fromBinary>::from_binary(bytes);
+            let slot = <u32 as fromBinary>::from_binary(bytes);
+            let name = read_name(bytes);
+            locals.push(LocalInfo{function, slot, name});
+        }
+        DebugInfo{files, functions, lines, locals}
+    }
+}
+
+impl ToBinary for DebugInfo {
+    fn to_binary(&self, out: &mut Vec<u8>) {
+        (self.files.len() as u32).to_binary(out);
+        for f in self.files.iter() {
+            f.to_binary(out);
+        }
+        (self.functions.len() as u32).to_binary(out);
+        for f in self.functions.iter() {
+            f.name.to_binary(out);
+            f.start.to_binary(out);
+            f.end.to_binary(out);
+        }
+        (self.lines.len() as u32).to_binary(out);
+        for l in self.lines.iter() {
+            l.pc.to_binary(out);
+            l.file.to_binary(out);
+            l.line.to_binary(out);
+            l.column.to_binary(out);
+        }
+        (self.locals.len() as u32).to_binary(out);
+        for l in self.locals.iter() {
+            l.function.to_binary(out);
+            l.slot.to_binary(out);
+            l.name.to_binary(out);
+        }
+    }
+}
+
+impl FunctionInfo {
+    pub fn len(&self) -> Result<u32, String> { // how many pcs the function spans, an error if it ends before it starts
+        self.end.checked_sub(self.start).ok_or_else(|| format!("Debug info function {} ends at pc {} before it starts at pc {}", self.name, self.end, self.start))
+    }
+}
+
+impl DebugInfo {
+    pub fn check(&self) -> Result<(), String> { // function to find what the accessors can't work with
+        for f in self.functions.iter() {
+            f.len()?;
+        }
+        if self.lines.windows(2).any(|w| w[0].pc > w[1].pc) {
+            return Err("Debug line table is not sorted by pc".to_string());
+        }
+        Ok(())
+    }
+
+    fn function_index(&self, pc: u32) -> Option<usize> { // the innermost function whose pcs include pc
+        let mut best: Option<(usize, u32)> = None;
+        for (k, f) in self.functions.iter().enumerate() {
+            let len = match f.len() {
+                Ok(len) => len,
+                Err(_) => continue // check() reports it
+            };
+            if f.start <= pc && pc < f.end && best.is_none_or(|(_, b)| len < b) {
+                best = Some((k, len));
+            }
+        }
+        best.map(|(k, _)| k)
+    }
+
+    pub fn function_at(&self, pc: u32) -> Option<&FunctionInfo> {
+        self.function_index(pc).map(|k| &self.functions[k])
+    }
+
+    pub fn line_at(&self, pc: u32) -> Option<(&str, u32, u32)> { // the source file, line and column pc came from
+        let k = match self.lines.binary_search_by(|l| l.pc.cmp(&pc)) {
+            Ok(k) => { // the last of several entries for the same pc
+                let mut k = k;
+                while k + 1 < self.lines.len() && self.lines[k + 1].pc == pc { k += 1; }
+                k
+            }
+            Err(0) => return None,
+            Err(k) => k - 1
+        };
+        let l = &self.lines[k];
+        if l.file == NO_FILE {
+            return None;
+        }
+        match self.files.get(l.file as usize) {
+            Some(file) => Some((file, l.line, l.column)),
+            None => None // a bad file index
+        }
+    }
+
+    pub fn local_name(&self, pc: u32, slot: u32) -> Option<&str> { // the name of frame slot slot in the function running at pc
+        let f = self.function_index(pc)? as u32;
+        self.locals.iter().find(|l| l.function == f && l.slot == slot).map(|l| l.name.as_str())
+    }
+
+    pub fn local_slot(&self, pc: u32, name: &str) -> Option<u32> { // the frame slot called name in the function running at pc
+        let f = self.function_index(pc)? as u32;
+        self.locals.iter().find(|l| l.function == f && l.name == name).map(|l| l.slot)
+    }
+
+    pub fn remap<F: Fn(u32) -> u32>(&self, f: F) -> Result<DebugInfo, String> { // function to move every pc, for the optimizer, an error if two different source positions land on one pc
+        let mut d = self.clone();
+        for func in d.functions.iter_mut() {
+            func.start = f(func.start);
+            func.end = f(func.end);
+        }
+        let mut lines: Vec<LineEntry> = Vec::new();
+        for l in self.lines.iter() {
+            let pc = f(l.pc);
+            match lines.last() {
+                Some(last) if last.pc == pc => {
+                    if (last.file, last.line, last.column) != (l.file, l.line, l.column) {
+                        let file = |k: u32| self.files.get(k as usize).map_or("?", |f| f.as_str());
+                        return Err(format!("Debug line entries for {}:{}:{} and {}:{}:{} both land on pc {}",
+                                           file(last.file), last.line, last.column, file(l.file), l.line, l.column, pc));
+                    }
+                }
+                _ => lines.push(LineEntry{pc, file: l.file, line: l.line, column: l.column})
+            }
+        }
+        d.lines = lines;
+        Ok(d)
+    }
+
+    pub fn append(&mut self, other: &DebugInfo, base: u32) { // function to add the debug info of code linked in right after self's, at base
+        let files = self.files.len() as u32;
+        let functions = self.functions.len() as u32;
+        if self.lines.last().is_some_and(|l| l.file != NO_FILE) && other.lines.first().is_none_or(|l| l.pc > 0) {
+            self.lines.push(LineEntry{pc: base, file: NO_FILE, line: 0, column: 0}); // self's last line ends where the new code begins
+        }
+        self.files.extend(other.files.iter().cloned());
+        for f in other.functions.iter() {
+            self.functions.push(FunctionInfo{name: f.name.clone(), start: f.start + base, end: f.end + base});
+        }
+        for l in other.lines.iter() {
+            let file = if l.file == NO_FILE { NO_FILE } else { l.file + files };
+            self.lines.push(LineEntry{pc: l.pc + base, file, line: l.line, column: l.column});
+        }
+        for l in other.locals.iter() {
+            self.locals.push(LocalInfo{function: l.function + functions, slot: l.slot, name: l.name.clone()});
+        }
+    }
+}
+
+pub fn describe(program: &Program, pc: u32) -> String { // function to say where pc is: `pc 12 in fact (fact.ml:3:18)`, or just `pc 12` without debug info
+    let d = match program.debug {
+        Some(ref d) => d,
+        None => return format!("pc {}", pc)
+    };
+    let mut out = match d.function_at(pc) {
+        Some(f) => format!("pc {} in {}", pc, f.name),
+        None => format!("pc {}", pc)
+    };
+    if let Some((file, line, column)) = d.line_at(pc) {
+        write!(out, " ({}:{}:{})", file, line, column).unwrap();
+    }
+    out
+}
+
+pub fn function_name(program: &Program, entry: u32) -> String { // functions go by their debug info or export name, the rest by where they start
+    if let Some(f) = program.debug.as_ref().and_then(|d| d.function_at(entry)) {
+        if f.start == entry {
+            return f.name.clone();
+        }
+    }
+    match program.exports.iter().find(|e| e.1 == entry) {
+        Some(e) => e.0.clone(),
+        None => format!("L{}", entry)
+    }
+}
+
+fn annotation(program: &Program, pc: u32) -> String { // the comment the disassembler puts after an instruction
+    let d = match program.debug {
+        Some(ref d) => d,
+        None => return String::new()
+    };
+    let mut notes: Vec<String> = Vec::new();
+    if let Some(f) = d.function_at(pc) {
+        if f.start == pc {
+            notes.push(format!("{}:", f.name));
+        }
+    }
+    if let Some((file, line, column)) = d.line_at(pc) {
+        let first = pc == 0 || d.line_at(pc - 1) != Some((file, line, column));
+        if first {
+            notes.push(format!("{}:{}:{}", file, line, column));
+        }
+    }
+    match program.code[pc as usize] {
+        Instr::Var(i) | Instr::Store(i) => {
+            if let Some(name) = d.local_name(pc, i) {
+                notes.push(name.to_string());
+            }
+        }
+        _ => ()
+    }
+    if notes.is_empty() { String::new() } else { format!("  ; {}", notes.join(" ")) }
+}
+
+pub fn disassemble(program: &Program) -> String { // function to list the program one instruction per line, so pc n is on line n+1, with what the debug info says about it
+    let mut out = String::new();
+    for (pc, i) in program.code.iter().enumerate() {
+        writeln!(out, "{:>6}  {}{}", pc, i, annotation(program, pc as u32)).unwrap();
+    }
+    out
+}
+
+pub fn backtrace(program: &Program, s: &State, pc: u32) -> String { // function to list the calls that led to pc, innermost first, from the return addresses on the control stack
+    let mut out = String::new();
+    writeln!(out, "  #0 {}", describe(program, pc)).unwrap();
+    let mut depth = 1;
+    for f in s.control.iter().rev() {
+        if let Some(ret) = f.ret {
+            if ret > 0 && ret != super::RETURN_TO_HOST {
+                writeln!(out, "  #{} {}", depth, describe(program, ret - 1)).unwrap(); // the Call just before the return address
+                depth += 1;
+            }
+        }
+    }
+    out
+}
+
+pub fn exec_trace(program: &Program, s: &mut State) { // function to run the program like exec does, printing each instruction and where it came from to stderr
+    'mainloop: loop{
+        if s.halt { break 'mainloop }
+        let pc = s.pc;
+        let i = match fetch(&program.code, s){
+            Ok(i) => i,
+            Err(e) => panic!("exec: {}", e)
+        };
+        eprintln!("{:<40} {}{}", describe(program, pc), i, annotation(program, pc));
+        if let Err(e) = step(i, s) {
+            panic!("{}\n{}", e, backtrace(program, s, pc));
+        }
+    }
+    let result = s.stack.pop().unwrap();
+    print!("{:?}",result);
+}
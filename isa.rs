@@ -0,0 +1,288 @@
+// The instruction set, declared once
+// Every instruction is one line of the table at the bottom: its name and operands, the byte it is
+// encoded as (None for the ones only the optimizer makes), what it does to the value stack and what
+// it does. The instructions! macro turns the table into the Instr enum, its decoder and encoder,
+// its mnemonic and printed form, its stack effect, and the reference `vm file.o --isa` prints.
+// Adding an instruction means adding a line here and an arm to evaluate in VM.rs; anything else
+// that matches on every instruction stops compiling until it is taught the new one.
+// SetFrame and Call still push the old fp and the return address onto the value stack, where the
+// frame layout has always had them, but they are dead slots kept for compatibility: Ret goes back
+// through the control stack, so a program may Store over them.
+// Unary and binary operators get the same treatment from operators!, and value tags are the TAG_
+// constants below, shared by Val's decoder and encoder.
+
+use std::fmt;
+use std::fmt::Write;
+use std::slice::Iter;
+
+use super::{Val, fromBinary};
+use object::ToBinary;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Effect {
+    Fixed(u32, u32), //Fixed(pops, pushes)
+    Native,          //Pops the arguments of the native called and pushes its result
+    Return           //Pops the return value, drops the callee's frame and pushes the return value
+}
+
+#[derive(Debug)]
+pub struct Opcode {
+    pub name: &'static str,
+    pub byte: Option<u8>,               //None for instructions made by the optimizer
+    pub operands: &'static [&'static str],
+    pub effect: Effect,
+    pub doc: &'static str
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Effect::Fixed(pops, pushes) => write!(f, "{} -> {}", pops, pushes),
+            Effect::Native => write!(f, "arity -> 1"),
+            Effect::Return => write!(f, "frame -> 1")
+        }
+    }
+}
+
+macro_rules! instructions {
+    ($( $name:ident $( ( $($arg:ident : $ty:ident),+ ) )* = $byte:expr, $effect:expr, $doc:expr; )*) => {
+        #[derive(Debug,Clone)]
+        pub enum Instr {
+            $( #[doc = $doc] $name $( ( $($ty),+ ) )* ),*
+        }
+
+        pub const OPCODES: &'static [Opcode] = &[
+            $( Opcode{name: stringify!($name), byte: $byte, operands: &[$($( stringify!($ty) ),+)*], effect: $effect, doc: $doc} ),*
+        ];
+
+        impl fromBinary for Instr { // function to convert binary into instructions
+            fn from_binary(bytes: &mut Iter<u8>) -> Self {
+                let byte = *bytes.next().unwrap();
+                $( if $byte == Some(byte) {
+                    return Instr::$name $( ( $( <$ty as fromBinary>::from_binary(bytes) ),+ ) )*;
+                } )*
+                panic!("Bad! No instruction has opcode {}", byte)
+            }
+        }
+
+        impl ToBinary for Instr {
+            fn to_binary(&self, out: &mut Vec<u8>) {
+                match *self {
+                    $( Instr::$name $( ( $( ref $arg ),+ ) )* => {
+                        match $byte {
+                            Some(b) => out.push(b),
+                            None => panic!("Bad! {:?} is made by the optimizer and has no binary encoding", self)
+                        }
+                        $($( $arg.to_binary(out); )+)*
+                    } )*
+                }
+            }
+        }
+
+        impl fmt::Display for Instr { // the way the disassembler and --trace show an instruction: Push(Vi32(3))
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    $( Instr::$name $( ( $( ref $arg ),+ ) )* => {
+                        write!(f, "{}", stringify!($name))?;
+                        $( let operands: Vec<String> = vec![$( format!("{:?}", $arg) ),+];
+                           write!(f, "({})", operands.join(", "))?; )*
+                        Ok(())
+                    } )*
+                }
+            }
+        }
+
+        pub fn opcode(i: &Instr) -> &'static Opcode { // function to find the table line of an instruction
+            let mut k = 0;
+            $( if let Instr::$name { .. } = *i { return &OPCODES[k]; } k += 1; )*
+            unreachable!("{} opcodes in the table", k)
+        }
+    }
+}
+
+macro_rules! operators {
+    ($table:ident, $op:ident, $( $name:ident = $byte:expr, $doc:expr; )*) => {
+        #[derive(Debug,Clone)]
+        pub enum $op {
+            $( #[doc = $doc] $name ),*
+        }
+
+        pub const $table: &'static [(&'static str, u8, &'static str)] = &[$( (stringify!($name), $byte, $doc) ),*];
+
+        impl fromBinary for $op {
+            fn from_binary(bytes: &mut Iter<u8>) -> Self {
+                let byte = *bytes.next().unwrap();
+                $( if byte == $byte { return $op::$name; } )*
+                panic!("Bad! No {} has code {}", stringify!($op), byte)
+            }
+        }
+
+        impl ToBinary for $op {
+            fn to_binary(&self, out: &mut Vec<u8>) {
+                out.push(match *self { $( $op::$name => $byte ),* });
+            }
+        }
+    }
+}
+
+operators!{UNOPS, Unop,
+    Neg = 0b0000_0000, "Boolean negation";
+}
+
+operators!{BINOPS, Binop,
+    Add = 0b0000_0000, "i32 addition";
+    Mul = 0b0000_0001, "i32 multiplication";
+    Sub = 0b0000_0010, "i32 subtraction";
+    Div = 0b0000_0011, "i32 division (raises an error on divide by zero and on i32::MIN / -1)";
+    Lt  = 0b0000_0100, "Returns true if one i32 is less than another, otherwise false";
+    Eq  = 0b0000_0101, "Returns true if one i32 is equal another, otherwise false, and Vunit is only equal to Vunit";
+}
+
+pub const TAG_UNIT: u8 = 0b0000_0000;
+pub const TAG_I32: u8 = 0b0000_0001;   //then the i32
+pub const TAG_TRUE: u8 = 0b0000_0010;
+pub const TAG_FALSE: u8 = 0b0000_0011;
+pub const TAG_LOC: u8 = 0b0000_0100;   //then the u32
+pub const TAG_UNDEF: u8 = 0b0000_0101;
+pub const TAG_SIZE: u8 = 0b0000_0110;  //then the i32
+pub const TAG_ADDR: u8 = 0b0000_0111;  //then the address as a u32
+
+const VALUES: &[(&str, u8, &str)] = &[
+    ("Vunit", TAG_UNIT, "The unit value"), ("Vi32", TAG_I32, "Then the i32"), ("Vbool(true)", TAG_TRUE, "True"),
+    ("Vbool(false)", TAG_FALSE, "False"), ("Vloc", TAG_LOC, "Then the location as a u32"), ("Vundef", TAG_UNDEF, "The undefined value"),
+    ("Vsize", TAG_SIZE, "Then the object's length as an i32"), ("Vaddr", TAG_ADDR, "Then the heap address as a u32")
+];
+
+impl fromBinary for Val { // function to convert binary into Vals
+    fn from_binary(bytes: &mut Iter<u8>) -> Self {
+        match *bytes.next().unwrap(){
+            TAG_UNIT => Val::Vunit,
+            TAG_I32 => Val::Vi32(<i32 as fromBinary>::from_binary(bytes)),
+            TAG_TRUE => Val::Vbool(true),
+            TAG_FALSE => Val::Vbool(false),
+            TAG_LOC => Val::Vloc(<u32 as fromBinary>::from_binary(bytes)),
+            TAG_UNDEF => Val::Vundef,
+            TAG_SIZE => Val::Vsize(<i32 as fromBinary>::from_binary(bytes)),
+            TAG_ADDR => Val::Vaddr(<u32 as fromBinary>::from_binary(bytes) as usize),
+            t => panic!("Bad! Unknown value tag {}", t)
+        }
+    }
+}
+
+impl ToBinary for Val {
+    fn to_binary(&self, out: &mut Vec<u8>) {
+        match *self {
+            Val::Vunit => out.push(TAG_UNIT),
+            Val::Vi32(i) => { out.push(TAG_I32); i.to_binary(out); }
+            Val::Vbool(true) => out.push(TAG_TRUE),
+            Val::Vbool(false) => out.push(TAG_FALSE),
+            Val::Vloc(l) => { out.push(TAG_LOC); l.to_binary(out); }
+            Val::Vundef => out.push(TAG_UNDEF),
+            Val::Vsize(i) => { out.push(TAG_SIZE); i.to_binary(out); }
+            Val::Vaddr(a) => { out.push(TAG_ADDR); (a as u32).to_binary(out); } // heap addresses are far below u32::MAX
+        }
+    }
+}
+
+instructions!{
+    Push(v: Val)          = Some(0b0000_0000), Effect::Fixed(0, 1), "Push(v): Push value v onto the stack";
+    Pop                   = Some(0b0000_0001), Effect::Fixed(1, 0), "Pop a value from the stack, discarding it";
+    Peek(i: u32)          = Some(0b0000_0010), Effect::Fixed(0, 1), "Peek(i): Push onto the stack the value at stack position i, counting up from the bottom";
+    Unary(u: Unop)        = Some(0b0000_0011), Effect::Fixed(1, 1), "Unary(u): Apply u to the top value on the stack";
+    Binary(b: Binop)      = Some(0b0000_0100), Effect::Fixed(2, 1), "Binary(b): Apply b to the top two values on the stack, replacing them with the result";
+    Swap                  = Some(0b0000_0101), Effect::Fixed(2, 2), "Swap the top two values";
+    Alloc                 = Some(0b0000_0110), Effect::Fixed(2, 1), "Allocate an array of n copies of v on the heap, with n, v on the stack, and push its Vaddr";
+    Set                   = Some(0b0000_0111), Effect::Fixed(3, 0), "Write v to arr[i], with arr, i, v on the stack";
+    Get                   = Some(0b0000_1000), Effect::Fixed(2, 1), "Push arr[i], with arr, i on the stack";
+    Var(i: u32)           = Some(0b0000_1001), Effect::Fixed(0, 1), "Var(i): Get the value at stack position fp+i";
+    Store(i: u32)         = Some(0b0000_1010), Effect::Fixed(1, 0), "Store(i): Store a value at stack position fp+i";
+    SetFrame(i: u32)      = Some(0b0000_1011), Effect::Fixed(0, 1), "SetFrame(i): Open a frame with fp = s.stack.len() - i, pushing the old fp as a Vloc nothing reads again, the control stack keeps the real one";
+    Call                  = Some(0b0000_1100), Effect::Fixed(1, 1), "Function call to the Vloc on top of the stack, which is replaced by the return address as a Vloc nothing reads again, Ret goes back through the control stack";
+    Ret                   = Some(0b0000_1101), Effect::Return, "Function return, dropping the frame and leaving the top value for the caller";
+    Branch                = Some(0b0000_1110), Effect::Fixed(2, 0), "Jump to the Vloc on top of the stack if the Vbool below it is true";
+    Halt                  = Some(0b0000_1111), Effect::Fixed(0, 0), "Halt the machine";
+    ReadChar              = Some(0b0001_0000), Effect::Fixed(0, 1), "Push the next input byte as an i32, or Vunit at end of input";
+    ReadInt               = Some(0b0001_0001), Effect::Fixed(0, 1), "Push the next whitespace-separated input integer, or Vunit at end of input";
+    PushHandler(l: u32)   = Some(0b0001_0010), Effect::Fixed(0, 0), "PushHandler(l): Install a handler at instruction l for values thrown from here on";
+    PopHandler            = Some(0b0001_0011), Effect::Fixed(0, 0), "Remove the innermost handler";
+    Print                 = Some(0b0001_0100), Effect::Fixed(1, 0), "Pop an i32 and write it to the output as a byte";
+    Throw                 = Some(0b0001_0101), Effect::Fixed(1, 0), "Throw the top value to the innermost handler";
+    Len                   = Some(0b0001_0110), Effect::Fixed(1, 1), "Replace the Vaddr on top of the stack with the length of the object it points to";
+    ArrayCopy             = Some(0b0001_0111), Effect::Fixed(5, 0), "Copy count values from src[i..] to dst[j..], with src, i, dst, j, count on the stack";
+    ArrayFill             = Some(0b0001_1000), Effect::Fixed(4, 0), "Write v to arr[i..i+count], with arr, i, count, v on the stack";
+    CallNative(i: u32)    = Some(0b0001_1001), Effect::Native, "CallNative(i): Pop the arguments of native i, call it, and push its result";
+    PushConst(i: u32)     = Some(0b0001_1010), Effect::Fixed(0, 1), "PushConst(i): Push the Vaddr of constant object i, turned into a Push when the program's data is loaded";
+    AddImm(c: i32)        = None, Effect::Fixed(1, 1), "AddImm(c): Push(Vi32(c)); Binary(Add), made by the optimizer";
+    VarVarBinary(i: u32, j: u32, b: Binop) = None, Effect::Fixed(0, 1), "VarVarBinary(i, j, b): Var(i); Var(j); Binary(b), made by the optimizer";
+    Jump(l: u32)          = None, Effect::Fixed(0, 0), "Jump(l): Push(Vbool(true)); Push(Vloc(l)); Branch, made by the optimizer";
+}
+
+pub fn reference() -> String { // function to write the instruction set out as markdown
+    let mut out = String::new();
+    writeln!(out, "# Instruction set\n").unwrap();
+    writeln!(out, "Operands follow the opcode byte, big endian. Stack effects are values popped -> values pushed.\n").unwrap();
+    writeln!(out, "| Opcode | Instruction | Operands | Stack | Description |").unwrap();
+    writeln!(out, "|--------|-------------|----------|-------|-------------|").unwrap();
+    for o in OPCODES.iter() {
+        let byte = match o.byte {
+            Some(b) => format!("0x{:02x}", b),
+            None => "-".to_string()
+        };
+        writeln!(out, "| {} | {} | {} | {} | {} |", byte, o.name, o.operands.join(", "), o.effect, o.doc).unwrap();
+    }
+    for &(title, table) in [("Unary operators", UNOPS), ("Binary operators", BINOPS), ("Values", VALUES)].iter() {
+        writeln!(out, "\n## {}\n", title).unwrap();
+        writeln!(out, "| Code | Name | Description |").unwrap();
+        writeln!(out, "|------|------|-------------|").unwrap();
+        for &(name, byte, doc) in table.iter() {
+            writeln!(out, "| 0x{:02x} | {} | {} |", byte, name, doc).unwrap();
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: ToBinary + fromBinary>(x: &T) -> (Vec<u8>, T) { // function to encode x, decode it back and check every byte was read
+        let mut bytes = Vec::new();
+        x.to_binary(&mut bytes);
+        let mut iter = bytes.iter();
+        let back = T::from_binary(&mut iter);
+        assert_eq!(iter.len(), 0, "bytes left over after decoding");
+        (bytes, back)
+    }
+
+    #[test]
+    fn every_val_round_trips() {
+        let values = [Val::Vunit, Val::Vi32(0), Val::Vi32(-7), Val::Vi32(i32::MIN), Val::Vi32(i32::MAX), Val::Vbool(true),
+                      Val::Vbool(false), Val::Vloc(0), Val::Vloc(u32::MAX), Val::Vundef, Val::Vsize(0), Val::Vsize(-1),
+                      Val::Vsize(i32::MAX), Val::Vaddr(0), Val::Vaddr(u32::MAX as usize)];
+        for v in values.iter() {
+            assert_eq!(round_trip(v).1, *v);
+        }
+        for &(name, tag, _) in VALUES.iter() { // every tag in the reference is decoded by some value above
+            assert!(values.iter().any(|v| round_trip(v).0[0] == tag), "no value with tag {} ({})", tag, name);
+        }
+    }
+
+    #[test]
+    fn every_instr_round_trips() {
+        let instrs = [Instr::Push(Val::Vi32(3)), Instr::Push(Val::Vaddr(9)), Instr::Pop, Instr::Peek(4), Instr::Unary(Unop::Neg),
+                      Instr::Binary(Binop::Add), Instr::Binary(Binop::Mul), Instr::Binary(Binop::Sub), Instr::Binary(Binop::Div),
+                      Instr::Binary(Binop::Lt), Instr::Binary(Binop::Eq), Instr::Swap, Instr::Alloc, Instr::Set, Instr::Get,
+                      Instr::Var(1), Instr::Store(u32::MAX), Instr::SetFrame(2), Instr::Call, Instr::Ret, Instr::Branch,
+                      Instr::Halt, Instr::ReadChar, Instr::ReadInt, Instr::PushHandler(12), Instr::PopHandler, Instr::Print, Instr::Throw,
+                      Instr::Len, Instr::ArrayCopy, Instr::ArrayFill, Instr::CallNative(1), Instr::PushConst(0)];
+        for i in instrs.iter() {
+            let (bytes, back) = round_trip(i);
+            assert_eq!(Some(bytes[0]), opcode(i).byte);
+            assert_eq!(format!("{:?}", back), format!("{:?}", i));
+        }
+        for o in OPCODES.iter() { // every encodable line of the table is covered, and only the optimizer's have no byte
+            let covered = instrs.iter().any(|i| opcode(i).name == o.name);
+            assert_eq!(covered, o.byte.is_some(), "{} is not round-tripped", o.name);
+        }
+    }
+}
@@ -0,0 +1,598 @@
+// Cranelift JIT for hot bytecode functions, built with `cargo build --features jit`
+// A function is everything reachable from a Call target without crossing a Ret, Halt or Throw.
+// Each one becomes a single native function with an entry point per basic block, so the
+// interpreter can jump into it at a call target, a branch target or the instruction after a Call.
+// Inside a block i32 and bool values live in registers, everything else goes through the interpreter.
+
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, Signature, UserFuncName, Value, Block};
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Switch};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+use std::collections::{BTreeSet, HashMap};
+use std::rc::Rc;
+
+use super::{State, Instr, Val, Binop, Unop, VmError, Word, fetch, step};
+use super::word;
+
+pub const JIT_THRESHOLD: u32 = 10; //How many calls make a function hot
+
+const JIT_ERROR: u32 = u32::MAX - 1; //Returned by compiled code when the error slot has been filled
+const TAG_I32: i64 = word::TAG_I32 as i64; //jit_peek and jit_var hand back the raw Word, tag in the upper half
+const TAG_BOOL: i64 = word::TAG_BOOL as i64;
+
+type JitFn = unsafe extern "C" fn(*mut State, *mut Option<VmError>, u32) -> u32;
+
+pub struct Jit {
+    module: JITModule,
+    program: Rc<[Instr]>,      //The code being compiled, compiled code points into it so it lives as long as the Jit
+    threshold: u32,            //Calls before a function is compiled, 0 compiles everything on first sight
+    calls: HashMap<u32, u32>,  //How many times each function entry has been called
+    entries: Vec<Option<JitFn>>, //The compiled function to enter at each pc, if any
+    seen: BTreeSet<u32>,       //Function entries already compiled or rejected
+    pub compiled: u32          //How many functions have been compiled
+}
+
+#[derive(Clone,Copy,PartialEq)]
+enum Kind {
+    I32,
+    Bool
+}
+
+impl Jit {
+    pub fn new(program: &[Instr], threshold: u32) -> Jit {
+        let mut flag_builder = settings::builder();
+        flag_builder.set("use_colocated_libcalls", "false").unwrap();
+        flag_builder.set("is_pic", "false").unwrap();
+        let isa = cranelift_native::builder().expect("host machine is not supported by the jit")
+            .finish(settings::Flags::new(flag_builder)).unwrap();
+        Jit{module: JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())), program: program.into(), threshold,
+            calls: HashMap::new(), entries: Vec::new(), seen: BTreeSet::new(), compiled: 0}
+    }
+
+    fn note_call(&mut self, target: u32) { // function to count a call and compile the callee once it is hot
+        let count = self.calls.entry(target).or_insert(0);
+        *count += 1;
+        if *count >= self.threshold {
+            self.compile(target);
+        }
+    }
+
+    fn compile(&mut self, entry: u32) { // function to compile the function starting at entry, unless it has been tried before
+        let program = self.program.clone();
+        if !self.seen.insert(entry) || entry as usize >= program.len() {
+            return;
+        }
+        let leaders = find_leaders(&program, entry);
+        let f = self.translate(&program, &leaders);
+        if self.entries.len() < program.len() {
+            self.entries.resize(program.len(), None);
+        }
+        for l in leaders.iter() {
+            if self.entries[*l as usize].is_none() {
+                self.entries[*l as usize] = Some(f);
+            }
+        }
+        self.compiled += 1;
+    }
+
+    fn translate(&mut self, program: &[Instr], leaders: &BTreeSet<u32>) -> JitFn { // function to turn one bytecode function into native code
+        let ptr = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(ptr));
+        sig.params.push(AbiParam::new(ptr));
+        sig.params.push(AbiParam::new(types::I32));
+        sig.returns.push(AbiParam::new(types::I32));
+        let id = self.module.declare_anonymous_function(&sig).unwrap();
+
+        let mut ctx = self.module.make_context();
+        ctx.func.signature = sig;
+        ctx.func.name = UserFuncName::user(0, id.as_u32());
+        let mut fctx = FunctionBuilderContext::new();
+        {
+            let mut b = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+            let start = b.create_block();
+            b.append_block_params_for_function_params(start);
+            b.switch_to_block(start);
+            let state = b.block_params(start)[0];
+            let err = b.block_params(start)[1];
+            let entry_pc = b.block_params(start)[2];
+
+            let mut blocks = HashMap::new();
+            for l in leaders.iter() {
+                blocks.insert(*l, b.create_block());
+            }
+            let mut t = Translator{b, ptr, state, err, blocks, stack: Vec::new()};
+
+            let unknown = t.b.create_block(); // entered at a pc this function doesn't start a block at
+            let mut switch = Switch::new();
+            for l in leaders.iter() {
+                switch.set_entry(*l as u128, t.blocks[l]);
+            }
+            switch.emit(&mut t.b, entry_pc, unknown);
+            t.b.switch_to_block(unknown);
+            t.b.ins().return_(&[entry_pc]);
+
+            for l in leaders.iter() {
+                t.block(program, leaders, *l);
+            }
+            let mut b = t.b;
+            b.seal_all_blocks();
+            b.finalize();
+        }
+        self.module.define_function(id, &mut ctx).unwrap();
+        self.module.clear_context(&mut ctx);
+        self.module.finalize_definitions().unwrap();
+        let code = self.module.get_finalized_function(id);
+        unsafe { std::mem::transmute::<*const u8, JitFn>(code) }
+    }
+
+    pub fn run(&mut self, s: &mut State) -> Result<(), VmError> { // function to run compiled code from s.pc, if there is any, until it hands back to the interpreter
+        let f = match self.entries.get(s.pc as usize) {
+            Some(Some(f)) => *f,
+            _ => return Ok(())
+        };
+        let mut err: Option<VmError> = None;
+        let pc = s.pc;
+        let next = unsafe { f(s as *mut State, &mut err as *mut Option<VmError>, pc) };
+        match err {
+            Some(e) => Err(e),
+            None => {
+                s.pc = next;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn find_leaders(program: &[Instr], entry: u32) -> BTreeSet<u32> { // function to find the first instruction of every basic block reachable from entry
+    let mut leaders = BTreeSet::new();
+    let mut seen = BTreeSet::new();
+    let mut work = vec![entry];
+    leaders.insert(entry);
+    while let Some(pc) = work.pop() {
+        if pc as usize >= program.len() || !seen.insert(pc) {
+            continue;
+        }
+        let mut next = vec![];
+        match &program[pc as usize] {
+            Instr::Ret | Instr::Halt | Instr::Throw => (),
+            Instr::Push(Val::Vloc(target)) if is_branch(program, pc + 1) => { // constant branch, both ways start a block
+                leaders.insert(*target);
+                leaders.insert(pc + 2);
+                next.push(*target);
+                next.push(pc + 2);
+            }
+            Instr::Branch | Instr::Call => { // unknown target, but we come back to the next instruction
+                leaders.insert(pc + 1);
+                next.push(pc + 1);
+            }
+            Instr::Jump(target) => {
+                leaders.insert(*target);
+                next.push(*target);
+            }
+            Instr::PushHandler(target) => { // a throw resumes at the handler
+                leaders.insert(*target);
+                next.push(*target);
+                next.push(pc + 1);
+            }
+            _ => next.push(pc + 1)
+        }
+        work.extend(next);
+    }
+    leaders.into_iter().filter(|l| (*l as usize) < program.len()).collect()
+}
+
+fn is_branch(program: &[Instr], pc: u32) -> bool {
+    matches!(program.get(pc as usize), Some(Instr::Branch))
+}
+
+struct Translator<'a> {
+    b: FunctionBuilder<'a>,
+    ptr: types::Type,
+    state: Value,
+    err: Value,
+    blocks: HashMap<u32, Block>,
+    stack: Vec<(Kind, Value)> //Values pushed in this block that haven't been written to s.stack yet
+}
+
+impl<'a> Translator<'a> {
+    fn helper(&mut self, f: usize, params: &[types::Type], ret: Option<types::Type>, args: &[Value]) -> Option<Value> { // function to call one of the jit_* helpers below
+        let mut sig = Signature::new(self.b.func.signature.call_conv);
+        for p in params {
+            sig.params.push(AbiParam::new(*p));
+        }
+        if let Some(r) = ret {
+            sig.returns.push(AbiParam::new(r));
+        }
+        let sigref = self.b.import_signature(sig);
+        let callee = self.b.ins().iconst(self.ptr, f as i64);
+        let call = self.b.ins().call_indirect(sigref, callee, args);
+        self.b.inst_results(call).first().cloned()
+    }
+
+    fn flush(&mut self) { // function to write the pending values to s.stack, bottom first
+        let pending: Vec<(Kind, Value)> = self.stack.drain(..).collect();
+        for (k, v) in pending {
+            let f = match k {
+                Kind::I32 => jit_push_i32 as *const () as usize,
+                Kind::Bool => jit_push_bool as *const () as usize
+            };
+            let (p, s) = (self.ptr, self.state);
+            self.helper(f, &[p, types::I32], None, &[s, v]);
+        }
+    }
+
+    fn exit(&mut self, pc: u32) { // function to end the block by handing pc back to the interpreter
+        self.flush();
+        let pc = self.b.ins().iconst(types::I32, pc as i64);
+        self.b.ins().return_(&[pc]);
+    }
+
+    fn bail_if(&mut self, bad: Value, pc: u32) { // function to give pc back to the interpreter if bad is set, the stack as it was before pc
+        let bail = self.b.create_block();
+        let ok = self.b.create_block();
+        self.b.ins().brif(bad, bail, &[], ok, &[]);
+        self.b.switch_to_block(bail);
+        let pending = self.stack.clone();
+        self.exit(pc);
+        self.stack = pending;
+        self.b.switch_to_block(ok);
+    }
+
+    fn goto(&mut self, target: u32) { // function to jump to the block at target, or leave if it isn't ours
+        self.flush();
+        match self.blocks.get(&target) {
+            Some(blk) => {
+                let blk = *blk;
+                self.b.ins().jump(blk, &[]);
+            }
+            None => self.exit(target)
+        }
+    }
+
+    fn operands(&mut self, kinds: &[Kind], pc: u32) -> Option<Vec<Value>> { // function to take operands off the top of the stack, top first, bailing to the interpreter on a type mismatch
+        let mut vals = Vec::new();
+        let mut from_stack = 0;
+        let mut taken = 0;
+        for k in kinds {
+            if taken < self.stack.len() {
+                let (have, v) = self.stack[self.stack.len() - 1 - taken];
+                if have != *k {
+                    return None; // wrong type, let the interpreter raise the error
+                }
+                vals.push(v);
+                taken += 1;
+            }
+            else { // check s.stack now, but only drop the values once every operand checks out
+                let depth = self.b.ins().iconst(types::I32, from_stack as i64);
+                let (p, s) = (self.ptr, self.state);
+                let tagged = self.helper(jit_peek as *const () as usize, &[p, types::I32], Some(types::I64), &[s, depth]).unwrap();
+                let tag = self.b.ins().ushr_imm(tagged, 32);
+                let want = match k { Kind::I32 => TAG_I32, Kind::Bool => TAG_BOOL };
+                let bad = self.b.ins().icmp_imm(IntCC::NotEqual, tag, want);
+                self.bail_if(bad, pc);
+                vals.push(self.b.ins().ireduce(types::I32, tagged));
+                from_stack += 1;
+            }
+        }
+        let keep = self.stack.len() - taken;
+        self.stack.truncate(keep);
+        if from_stack > 0 {
+            let n = self.b.ins().iconst(types::I32, from_stack as i64);
+            let (p, s) = (self.ptr, self.state);
+            self.helper(jit_drop as *const () as usize, &[p, types::I32], None, &[s, n]);
+        }
+        Some(vals)
+    }
+
+    fn generic(&mut self, i: &Instr, pc: u32) { // function to run the instruction i at pc through the interpreter and carry on unless it jumped
+        self.flush();
+        let (p, s, e) = (self.ptr, self.state, self.err);
+        let instr = self.b.ins().iconst(p, i as *const Instr as i64); // the Jit keeps the program alive, so i stays put
+        let at = self.b.ins().iconst(types::I32, pc as i64);
+        let next = self.helper(jit_generic as *const () as usize, &[p, p, p, types::I32], Some(types::I32), &[s, e, instr, at]).unwrap();
+        let jumped = self.b.ins().icmp_imm(IntCC::NotEqual, next, (pc + 1) as i64);
+        let leave = self.b.create_block();
+        let carry_on = self.b.create_block();
+        self.b.ins().brif(jumped, leave, &[], carry_on, &[]);
+        self.b.switch_to_block(leave);
+        self.b.ins().return_(&[next]);
+        self.b.switch_to_block(carry_on);
+    }
+
+    fn block(&mut self, program: &[Instr], leaders: &BTreeSet<u32>, leader: u32) { // function to translate the basic block starting at leader
+        let blk = self.blocks[&leader];
+        self.b.switch_to_block(blk);
+        self.stack.clear();
+
+        let depth = max_pending(program, leaders, leader); // bail up front if the pending values could overflow the stack
+        if depth > 0 {
+            let (p, s) = (self.ptr, self.state);
+            let len = self.helper(jit_stack_len as *const () as usize, &[p], Some(types::I32), &[s]).unwrap();
+            let bad = self.b.ins().icmp_imm(IntCC::UnsignedGreaterThan, len, 1024u32.saturating_sub(depth) as i64);
+            self.bail_if(bad, leader);
+        }
+
+        let mut pc = leader;
+        loop {
+            if pc != leader && leaders.contains(&pc) { // fall through into the next block
+                self.goto(pc);
+                return;
+            }
+            let i = match program.get(pc as usize) {
+                Some(i) => i.clone(),
+                None => { // ran off the end, the interpreter reports it
+                    self.exit(pc);
+                    return;
+                }
+            };
+            match i {
+                Instr::Push(Val::Vi32(x)) => {
+                    let v = self.b.ins().iconst(types::I32, x as i64);
+                    self.stack.push((Kind::I32, v));
+                }
+                Instr::Push(Val::Vbool(x)) => {
+                    let v = self.b.ins().iconst(types::I32, x as i64);
+                    self.stack.push((Kind::Bool, v));
+                }
+                Instr::Push(Val::Vloc(target)) if is_branch(program, pc + 1) => {
+                    match self.operands(&[Kind::Bool], pc) {
+                        Some(vals) => {
+                            self.flush();
+                            let taken = self.b.create_block();
+                            let not_taken = self.b.create_block();
+                            self.b.ins().brif(vals[0], taken, &[], not_taken, &[]);
+                            self.b.switch_to_block(taken);
+                            self.goto(target);
+                            self.b.switch_to_block(not_taken);
+                            self.goto(pc + 2);
+                        }
+                        None => self.exit(pc)
+                    }
+                    return;
+                }
+                Instr::Pop if !self.stack.is_empty() => {
+                    self.stack.pop();
+                }
+                Instr::Swap if self.stack.len() >= 2 => {
+                    let top = self.stack.pop().unwrap();
+                    let second = self.stack.pop().unwrap();
+                    self.stack.push(top);
+                    self.stack.push(second);
+                }
+                Instr::Unary(Unop::Neg) => {
+                    match self.operands(&[Kind::Bool], pc) {
+                        Some(vals) => {
+                            let v = self.b.ins().bxor_imm(vals[0], 1);
+                            self.stack.push((Kind::Bool, v));
+                        }
+                        None => {
+                            self.exit(pc);
+                            return;
+                        }
+                    }
+                }
+                Instr::Binary(op) => {
+                    if !self.binary(op, pc) {
+                        self.exit(pc);
+                        return;
+                    }
+                }
+                Instr::AddImm(c) => {
+                    match self.operands(&[Kind::I32], pc) {
+                        Some(vals) => {
+                            let v = self.b.ins().iadd_imm(vals[0], c as i64);
+                            self.stack.push((Kind::I32, v));
+                        }
+                        None => {
+                            self.exit(pc);
+                            return;
+                        }
+                    }
+                }
+                Instr::VarVarBinary(x, y, op) => {
+                    match op {
+                        Binop::Div => self.generic(&program[pc as usize], pc), // Div can bail after the Vars, leave it to the interpreter
+                        _ => {
+                            let a = self.var(x, pc);
+                            let b = self.var(y, pc);
+                            self.stack.push((Kind::I32, a));
+                            self.stack.push((Kind::I32, b));
+                            self.binary(op, pc);
+                        }
+                    }
+                }
+                Instr::Jump(target) => {
+                    self.goto(target);
+                    return;
+                }
+                Instr::Var(x) => {
+                    let v = self.var(x, pc);
+                    self.stack.push((Kind::I32, v));
+                }
+                Instr::Store(x) if !self.stack.is_empty() => {
+                    let (k, v) = self.stack.pop().unwrap();
+                    let (p, s) = (self.ptr, self.state);
+                    let idx = self.b.ins().iconst(types::I32, x as i64);
+                    let kind = self.b.ins().iconst(types::I32, if k == Kind::I32 { TAG_I32 } else { TAG_BOOL });
+                    let ok = self.helper(jit_store as *const () as usize, &[p, types::I32, types::I32, types::I32], Some(types::I32), &[s, idx, kind, v]).unwrap();
+                    let bad = self.b.ins().icmp_imm(IntCC::Equal, ok, 0);
+                    self.stack.push((k, v)); // still pending if we have to bail
+                    self.bail_if(bad, pc);
+                    self.stack.pop();
+                }
+                Instr::Call | Instr::Ret | Instr::Halt | Instr::Throw | Instr::Branch => { // control flow is left to the interpreter
+                    self.exit(pc);
+                    return;
+                }
+                _ => self.generic(&program[pc as usize], pc)
+            }
+            pc += 1;
+        }
+    }
+
+    fn var(&mut self, x: u32, pc: u32) -> Value { // function to read frame slot x, bailing unless it holds an i32 already on s.stack
+        let (p, s) = (self.ptr, self.state);
+        let idx = self.b.ins().iconst(types::I32, x as i64);
+        let tagged = self.helper(jit_var as *const () as usize, &[p, types::I32], Some(types::I64), &[s, idx]).unwrap();
+        let tag = self.b.ins().ushr_imm(tagged, 32);
+        let bad = self.b.ins().icmp_imm(IntCC::NotEqual, tag, TAG_I32);
+        self.bail_if(bad, pc);
+        self.b.ins().ireduce(types::I32, tagged)
+    }
+
+    fn binary(&mut self, op: Binop, pc: u32) -> bool { // function to translate a Binary, returning false if the interpreter should do it
+        let vals = match self.operands(&[Kind::I32, Kind::I32], pc) { // an Eq on Vunit goes to the interpreter
+            Some(v) => v,
+            None => return false
+        };
+        let (v1, v2) = (vals[0], vals[1]); // v1 was on top, just like eval_binary
+        let (kind, v) = match op {
+            Binop::Add => (Kind::I32, self.b.ins().iadd(v1, v2)),
+            Binop::Sub => (Kind::I32, self.b.ins().isub(v1, v2)),
+            Binop::Mul => (Kind::I32, self.b.ins().imul(v1, v2)),
+            Binop::Div => {
+                let zero = self.b.ins().icmp_imm(IntCC::Equal, v2, 0); // the interpreter raises these
+                let min = self.b.ins().icmp_imm(IntCC::Equal, v1, i32::MIN as i64);
+                let minus_one = self.b.ins().icmp_imm(IntCC::Equal, v2, -1);
+                let overflow = self.b.ins().band(min, minus_one);
+                let bad = self.b.ins().bor(zero, overflow);
+                self.stack.push((Kind::I32, v2));
+                self.stack.push((Kind::I32, v1));
+                self.bail_if(bad, pc);
+                self.stack.pop();
+                self.stack.pop();
+                (Kind::I32, self.b.ins().sdiv(v1, v2))
+            }
+            Binop::Lt => {
+                let c = self.b.ins().icmp(IntCC::SignedLessThan, v1, v2);
+                (Kind::Bool, self.b.ins().uextend(types::I32, c))
+            }
+            Binop::Eq => {
+                let c = self.b.ins().icmp(IntCC::Equal, v1, v2);
+                (Kind::Bool, self.b.ins().uextend(types::I32, c))
+            }
+        };
+        self.stack.push((kind, v));
+        true
+    }
+}
+
+fn max_pending(program: &[Instr], leaders: &BTreeSet<u32>, leader: u32) -> u32 { // function to bound how many values a block can push before it writes them out
+    let mut depth: u32 = 0;
+    let mut max = 0;
+    let mut pc = leader;
+    while (pc as usize) < program.len() && (pc == leader || !leaders.contains(&pc)) {
+        match &program[pc as usize] {
+            Instr::Push(Val::Vi32(_)) | Instr::Push(Val::Vbool(_)) | Instr::Var(_) | Instr::AddImm(_) => depth += 1,
+            Instr::VarVarBinary(_, _, _) | Instr::Jump(_) => depth += 2,
+            Instr::Call | Instr::Ret | Instr::Halt | Instr::Throw | Instr::Branch => break,
+            _ => ()
+        }
+        if depth > max {
+            max = depth;
+        }
+        pc += 1;
+    }
+    max
+}
+
+// Helpers called from compiled code. They only ever see the State the interpreter lent to Jit::run.
+
+extern "C" fn jit_push_i32(s: *mut State, v: i32) {
+    let s = unsafe { &mut *s };
+    s.stack.push(Word::i32(v));
+}
+
+extern "C" fn jit_push_bool(s: *mut State, v: i32) {
+    let s = unsafe { &mut *s };
+    s.stack.push(Word::bool(v != 0));
+}
+
+fn tag(v: Option<&Word>) -> i64 { // function to hand compiled code a Word, a missing one reads as Vunit which never passes a tag check
+    match v {
+        Some(w) => w.bits() as i64,
+        None => Word::UNIT.bits() as i64
+    }
+}
+
+extern "C" fn jit_peek(s: *mut State, depth: i32) -> i64 {
+    let s = unsafe { &*s };
+    let len = s.stack.len();
+    if depth as usize >= len {
+        return 0;
+    }
+    tag(s.stack.get(len - 1 - depth as usize))
+}
+
+extern "C" fn jit_drop(s: *mut State, n: i32) {
+    let s = unsafe { &mut *s };
+    let len = s.stack.len();
+    s.stack.truncate(len - n as usize);
+}
+
+extern "C" fn jit_stack_len(s: *mut State) -> i32 {
+    let s = unsafe { &*s };
+    s.stack.len() as i32
+}
+
+extern "C" fn jit_var(s: *mut State, x: i32) -> i64 { // tag 0 if the slot is out of range or isn't an i32, the interpreter deals with it
+    let s = unsafe { &*s };
+    tag(s.stack.get(s.fp as usize + x as u32 as usize))
+}
+
+extern "C" fn jit_store(s: *mut State, x: i32, kind: i32, v: i32) -> i32 {
+    let s = unsafe { &mut *s };
+    let idx = s.fp as usize + x as u32 as usize;
+    if idx >= s.stack.len() {
+        return 0;
+    }
+    s.stack[idx] = if kind as i64 == TAG_I32 { Word::i32(v) } else { Word::bool(v != 0) };
+    1
+}
+
+extern "C" fn jit_generic(s: *mut State, err: *mut Option<VmError>, i: *const Instr, pc: i32) -> i32 { // runs instruction i at pc in the interpreter, returning where to go next
+    let s = unsafe { &mut *s };
+    let i = unsafe { &*i };
+    let pc = pc as u32;
+    s.pc = pc + 1;
+    match step(i, s) {
+        Ok(()) => s.pc as i32,
+        Err(e) => {
+            unsafe { *err = Some(e); }
+            JIT_ERROR as i32
+        }
+    }
+}
+
+pub fn exec_jit(s: &mut State, jit: &mut Jit) { // function to run the program, entering compiled code wherever there is some
+    if jit.threshold == 0 {
+        jit.compile(s.pc);
+    }
+    let program = jit.program.clone();
+    'mainloop: loop{
+        if s.halt { break 'mainloop }
+        if let Err(e) = jit.run(s) {
+            panic!("{}", e);
+        }
+        if s.halt { break 'mainloop }
+        let i = match fetch(&program, s){ // always interpret at least one instruction so bailing out makes progress
+            Ok(i) => i,
+            Err(e) => panic!("exec: {}", e)
+        };
+        let callee = match (i, s.stack.last().map(|w| w.val())) { // count calls so hot functions get compiled
+            (Instr::Call, Some(Val::Vloc(target))) => Some(target),
+            _ => None
+        };
+        if let Err(e) = step(i, s) {
+            panic!("{}", e);
+        }
+        if let Some(target) = callee {
+            jit.note_call(target);
+        }
+    }
+    let result = s.stack.pop().unwrap();
+    print!("{:?}",result);
+}
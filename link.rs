@@ -0,0 +1,178 @@
+// Relocatable objects and the linker, for `vm main.o --link lib.o [--link ...] [--write-object out.o]`
+// A relocatable object carries a relocations section saying which operands hold code addresses,
+// so they can be moved when the object is placed after others, and which Push(Vloc)s stand for
+// functions another object exports:
+//   externals    count:u32, then the names of symbols the object uses but doesn't define
+//   relocations  count:u32, then pc:u32 kind:u32 symbol:u32
+//     RELOC_CODE    the Push(Vloc(l)) or PushHandler(l) at pc holds an address in this object
+//     RELOC_SYMBOL  the Push(Vloc) at pc is the address of externals[symbol]
+// The linker lays the objects' code out one after another, the first object's at 0 so its entry
+// sequence runs first, and relocates every code address by where its object landed. Exports of all
+// the objects become the program's exports and resolve the externals; a name exported twice or
+// not exported at all is an error. Native imports are merged and CallNatives renumbered, constant
+// objects appended and PushConsts renumbered, and debug info appended. The linked program keeps
+// its code relocations, so it can itself be linked into something bigger as a library.
+
+use std::slice::Iter;
+
+use super::{Program, Instr, Val, fromBinary, read_name};
+use object::ToBinary;
+use debuginfo::DebugInfo;
+
+pub const RELOC_CODE: u32 = 0;
+pub const RELOC_SYMBOL: u32 = 1;
+
+#[derive(Debug,Clone)]
+pub struct Relocation {
+    pub pc: u32,     //The instruction whose operand is relocated
+    pub kind: u32,   //RELOC_CODE or RELOC_SYMBOL
+    pub symbol: u32  //Index into externals, for RELOC_SYMBOL
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct Relocations {
+    pub externals: Vec<String>,
+    pub relocations: Vec<Relocation>
+}
+
+impl fromBinary for Relocations { // function to read the relocations section
+    fn from_binary(bytes: &mut Iter<u8>) -> Self {
+        let count = <u32 as fromBinary>::from_binary(bytes);
+        let externals = (0..count).map(|_| read_name(bytes)).collect();
+        let count = <u32 as fromBinary>::from_binary(bytes);
+        let mut relocations = Vec::new();
+        for _ in 0..count {
+            let pc = <u32 as fromBinary>::from_binary(bytes);
+            let kind = <u32 as fromBinary>::from_binary(bytes);
+            let symbol = <u32 as fromBinary>::from_binary(bytes);
+            relocations.push(Relocation{pc, kind, symbol});
+        }
+        Relocations{externals, relocations}
+    }
+}
+
+impl ToBinary for Relocations {
+    fn to_binary(&self, out: &mut Vec<u8>) {
+        (self.externals.len() as u32).to_binary(out);
+        for name in self.externals.iter() {
+            name.to_binary(out);
+        }
+        (self.relocations.len() as u32).to_binary(out);
+        for r in self.relocations.iter() {
+            r.pc.to_binary(out);
+            r.kind.to_binary(out);
+            r.symbol.to_binary(out);
+        }
+    }
+}
+
+pub fn infer_relocations(program: &Program) -> Relocations { // function to mark every Push(Vloc) and PushHandler as a code address, which is all compilers targeting the vm use them for
+    let mut r = Relocations::default();
+    for (pc, i) in program.code.iter().enumerate() {
+        match *i {
+            Instr::Push(Val::Vloc(_)) | Instr::PushHandler(_) => r.relocations.push(Relocation{pc: pc as u32, kind: RELOC_CODE, symbol: 0}),
+            _ => ()
+        }
+    }
+    r
+}
+
+fn relocate(i: &mut Instr, base: u32) -> Result<(), String> { // function to move the code address operand of i along by base
+    match *i {
+        Instr::Push(Val::Vloc(ref mut l)) | Instr::PushHandler(ref mut l) => {
+            *l = l.checked_add(base).ok_or(format!("code address {} moved along by {} is past the end of a u32", l, base))?;
+        }
+        _ => return Err(format!("{:?} has no code address to relocate", i))
+    }
+    Ok(())
+}
+
+pub fn link(objects: &[(String, Program)]) -> Result<Program, String> { // function to link objects, named for error messages, into one program
+    let mut out = Program{code: Vec::new(), imports: Vec::new(), exports: Vec::new(), constants: Vec::new(),
+                          debug: None, heap: Vec::new(), relocations: Some(Relocations::default())};
+    if objects.iter().any(|o| o.1.debug.is_some()) {
+        out.debug = Some(DebugInfo::default());
+    }
+    let mut bases: Vec<u32> = Vec::new();
+    let mut owners: Vec<usize> = Vec::new(); //Which object each export came from
+    for (k, (name, p)) in objects.iter().enumerate() {
+        let base = out.code.len() as u32;
+        bases.push(base);
+        if k > 0 && p.relocations.is_none() {
+            return Err(format!("{} is not relocatable, only the first object may leave out its relocations", name));
+        }
+        if k > 0 && !p.heap.is_empty() {
+            return Err(format!("{} has an initial heap, only the first object may have one", name));
+        }
+        for (e, l) in p.exports.iter() {
+            if let Some(other) = out.exports.iter().position(|x| x.0 == *e) {
+                return Err(format!("{} is exported by both {} and {}", e, objects[owners[other]].0, name));
+            }
+            let l = l.checked_add(base).ok_or(format!("{}: export {} at {} moved along by {} is past the end of a u32", name, e, l, base))?;
+            out.exports.push((e.clone(), l));
+            owners.push(k);
+        }
+        let constants = out.constants.len() as u32;
+        out.constants.extend(p.constants.iter().cloned());
+        out.heap.extend(p.heap.iter().cloned());
+        if let Some(ref mut d) = out.debug {
+            d.append(p.debug.as_ref().unwrap_or(&DebugInfo::default()), base);
+        }
+        for i in p.code.iter() {
+            let mut i = i.clone();
+            match i {
+                Instr::CallNative(ref mut x) => { // renumbered into the merged import table
+                    let native = p.imports.get(*x as usize).ok_or(format!("{}: CallNative({}) has no entry in the import table", name, x))?;
+                    *x = match out.imports.iter().position(|n| n == native) {
+                        Some(n) => n as u32,
+                        None => {
+                            out.imports.push(native.clone());
+                            out.imports.len() as u32 - 1
+                        }
+                    };
+                }
+                Instr::PushConst(ref mut x) => *x += constants, // constant objects are numbered per object file
+                _ => ()
+            }
+            out.code.push(i);
+        }
+    }
+
+    for (k, (name, p)) in objects.iter().enumerate() { // now every object has a place and every export an address
+        let base = bases[k];
+        let r = match p.relocations {
+            Some(ref r) => r,
+            None => continue
+        };
+        for reloc in r.relocations.iter() {
+            if reloc.pc as usize >= p.code.len() {
+                return Err(format!("{}: relocation at pc {} is outside the code", name, reloc.pc));
+            }
+            let pc = reloc.pc as usize + base as usize;
+            match reloc.kind {
+                RELOC_CODE => {
+                    relocate(&mut out.code[pc], base).map_err(|e| format!("{}: pc {}: {}", name, reloc.pc, e))?;
+                    out.relocations.as_mut().unwrap().relocations.push(Relocation{pc: pc as u32, kind: RELOC_CODE, symbol: 0});
+                }
+                RELOC_SYMBOL => {
+                    let symbol = r.externals.get(reloc.symbol as usize)
+                        .ok_or(format!("{}: relocation at pc {} names external {}, which isn't there", name, reloc.pc, reloc.symbol))?;
+                    let address = match out.exports.iter().find(|e| e.0 == *symbol) {
+                        Some(e) => e.1,
+                        None => return Err(format!("{}: undefined symbol {}", name, symbol))
+                    };
+                    match out.code[pc] {
+                        Instr::Push(Val::Vloc(ref mut l)) => *l = address,
+                        ref i => return Err(format!("{}: pc {}: {:?} can't refer to a symbol", name, reloc.pc, i))
+                    }
+                    out.relocations.as_mut().unwrap().relocations.push(Relocation{pc: pc as u32, kind: RELOC_CODE, symbol: 0}); // resolved, it is an address in the program now
+                }
+                _ => return Err(format!("{}: unknown relocation kind {}", name, reloc.kind))
+            }
+        }
+    }
+    if objects.first().is_some_and(|o| o.1.relocations.is_none()) { // its addresses are only right where they are
+        out.relocations = None;
+    }
+    Ok(out)
+}
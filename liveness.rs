@@ -0,0 +1,160 @@
+// Liveness of frame slots, for the dead-store elimination --opt-level 2 does
+// Var(i) and Store(i) reach slot fp+i of the stack, and a function's locals are the values it
+// pushed after its entry, so a slot is also read when an instruction pops it. With the heights from
+// stack.rs and the fp its callers' SetFrames give a function, every popped stack position is a slot
+// too, and the usual backward analysis over the function's blocks finds the slots that are live
+// after each instruction: read by a Var, a pop other than Pop, the result Ret or Halt leaves, or by
+// a function called before being written again. A Store to a slot that is not live is dead.
+// Only functions whose frame is certain are analyzed: pc 0, which starts with fp 0 on an empty
+// stack, and functions that are only ever called directly, all with the same frame layout. Ones
+// that install handlers, call natives or Peek, whose heights don't agree, or that Branch or Call
+// somewhere the graph doesn't know, are left alone. A Store that could be out of range, or fed by
+// a Var that could, is never dead, so removing it can't take away a fault.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{Program, Instr, Val};
+use cfg::{self, Cfg, EdgeKind};
+use isa::{self, Effect};
+use stack::{self, FunctionStack};
+
+fn frame(program: &Program, cfg: &Cfg, functions: &[FunctionStack], k: usize) -> Option<i64> { // where function k's fp is relative to its entry height, if that is certain
+    let entry = cfg.functions[k].entry;
+    if entry == 0 {
+        return Some(0);
+    }
+    if program.exports.iter().any(|e| e.1 == entry) {
+        return None; // the host can call it with any number of arguments
+    }
+    let calls: Vec<&stack::CallSite> = functions.iter().flat_map(|f| f.calls.iter()).filter(|c| c.callee == entry).collect();
+    let pushes = program.code.iter().filter(|i| match **i { Instr::Push(Val::Vloc(l)) => l == entry, _ => false }).count();
+    if calls.is_empty() || calls.len() != pushes { // its address goes somewhere other than a Call
+        return None;
+    }
+    let fp = calls[0].fp?;
+    if fp > 0 {
+        return None; // its frame starts above its entry height, so what it pushes are not all locals
+    }
+    if calls.iter().all(|c| c.fp == Some(fp)) { Some(fp) } else { None }
+}
+
+fn leaves(program: &Program, cfg: &Cfg, b: usize) -> bool { // does every way out of block b go somewhere known?
+    let len = program.code.len() as u32;
+    let end = cfg.blocks[b].end;
+    let known = |sites: &[(u32, Option<u32>)]| sites.iter().any(|&(pc, t)| pc == end - 1 && t.is_some_and(|t| t < len));
+    match program.code[end as usize - 1] {
+        Instr::Branch => known(&cfg.branches) && end < len,
+        Instr::Call => known(&cfg.calls) && end < len,
+        Instr::Jump(l) => l < len,
+        Instr::Ret | Instr::Halt | Instr::Throw => true,
+        _ => end < len // falls off the end of the code otherwise
+    }
+}
+
+fn analyzable(program: &Program, cfg: &Cfg, f: &FunctionStack, k: usize) -> bool {
+    f.problems.is_empty() && cfg.functions[k].blocks.iter().all(|&b| {
+        let block = &cfg.blocks[b];
+        f.heights.contains_key(&block.start) && leaves(program, cfg, b) && !program.code[block.start as usize..block.end as usize].iter()
+            .any(|i| matches!(*i, Instr::PushHandler(_) | Instr::PopHandler | Instr::CallNative(_) | Instr::Peek(_)))
+    })
+}
+
+fn transfer(i: &Instr, h: i64, fp: i64, live: &mut BTreeSet<u32>) { // function to carry the slots live after i back to before it, h being the height before i
+    let slot = |p: i64| -> Option<u32> { // the slot of the stack position p values above the entry height, None below the frame
+        let x = p - fp;
+        if x >= 0 && x <= u32::MAX as i64 { Some(x as u32) } else { None }
+    };
+    let everything = |live: &mut BTreeSet<u32>| live.extend((fp.min(0)..h).filter_map(slot));
+    match *i {
+        Instr::Call | Instr::Halt => { // the callee reads its arguments, and the machine stops with the whole stack
+            everything(live);
+            return;
+        }
+        Instr::Ret | Instr::Throw => { // only the value handed back is read, the frame goes away
+            live.clear();
+            live.extend(slot(h - 1));
+            return;
+        }
+        _ => ()
+    }
+    let (pops, pushes) = match isa::opcode(i).effect {
+        Effect::Fixed(pops, pushes) => (pops as i64, pushes as i64),
+        _ => unreachable!() // Ret and CallNative were handled or ruled out
+    };
+    for x in (h - pops..h - pops + pushes).filter_map(slot) { // written
+        live.remove(&x);
+    }
+    if let Instr::Store(x) = *i {
+        live.remove(&x);
+    }
+    if let Instr::Pop = *i {
+        return;
+    }
+    live.extend((h - pops..h).filter_map(slot)); // read
+    match *i {
+        Instr::Var(x) => { live.insert(x); }
+        Instr::VarVarBinary(x, y, _) => { live.insert(x); live.insert(y); }
+        _ => ()
+    }
+}
+
+pub fn live_after(program: &Program, cfg: &Cfg, functions: &[FunctionStack], k: usize) -> Option<BTreeMap<u32, BTreeSet<u32>>> { // function to find the slots live after each instruction of function k, None if it can't be analyzed
+    let f = &functions[k];
+    if !analyzable(program, cfg, f, k) {
+        return None;
+    }
+    let fp = frame(program, cfg, functions, k)?;
+    let blocks = &cfg.functions[k].blocks;
+    let mut live_in: BTreeMap<usize, BTreeSet<u32>> = BTreeMap::new();
+    let mut after: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in blocks.iter().rev() {
+            let block = &cfg.blocks[b];
+            let mut live: BTreeSet<u32> = BTreeSet::new();
+            for e in block.succs.iter() {
+                match e.kind {
+                    EdgeKind::Call | EdgeKind::Return | EdgeKind::Handler => (),
+                    _ => if let Some(l) = live_in.get(&e.to) { live.extend(l.iter().cloned()); }
+                }
+            }
+            for pc in (block.start..block.end).rev() {
+                after.insert(pc, live.clone());
+                transfer(&program.code[pc as usize], f.heights[&pc], fp, &mut live);
+            }
+            if live_in.get(&b) != Some(&live) {
+                live_in.insert(b, live);
+                changed = true;
+            }
+        }
+    }
+    Some(after)
+}
+
+pub fn dead_stores(program: &Program) -> BTreeSet<u32> { // function to find every Store whose slot is not live after it, and which can't fault
+    let cfg = cfg::build(program);
+    let functions = stack::analyze_cfg(program, &[], &cfg);
+    let mut dead = BTreeSet::new();
+    for k in 0..cfg.functions.len() {
+        let after = match live_after(program, &cfg, &functions, k) {
+            Some(a) => a,
+            None => continue
+        };
+        let fp = frame(program, &cfg, &functions, k).unwrap(); // live_after found it
+        let heights = &functions[k].heights;
+        let inside = |x: u32, h: i64| fp + (x as i64) < h; // is slot x below height h?
+        for (pc, live) in after.iter() {
+            if let Instr::Store(x) = program.code[*pc as usize] {
+                let fed = match (*pc).checked_sub(1).map(|p| (&program.code[p as usize], heights.get(&p))) {
+                    Some((&Instr::Var(y), Some(&h))) => inside(y, h), // the Var the optimizer drops with it
+                    _ => true
+                };
+                if !live.contains(&x) && inside(x, heights[pc] - 1) && fed {
+                    dead.insert(*pc);
+                }
+            }
+        }
+    }
+    dead
+}
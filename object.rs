@@ -0,0 +1,192 @@
+// Object file container, and the encoder that writes programs back out
+// A legacy object file is a big-endian u32 instruction count, the instructions, then optional
+// import and export tables. The container wraps typed sections behind a magic number and version:
+//   "VMOB"  version:u32  section count:u32
+//   then for each section  kind:u32  length in bytes:u32  payload
+// The loader tells the two apart by the first four bytes: "VMOB" read as an instruction count
+// would be over a billion instructions. Sections of a kind this vm doesn't know are skipped, so
+// newer tools can add sections without breaking older vms; a newer major version is refused.
+// Payloads use the same encodings as the legacy layout:
+//   code       instruction count:u32, then the instructions
+//   constants  object count:u32, then for each object its field count:u32 and the fields
+//   symbols    the import table, then the export table
+//   debug      function names, source lines and local names, see debuginfo.rs
+//   heap       count:u32, then the values the heap starts out holding
+//   relocations  code addresses and external symbols of a relocatable object, see link.rs
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::slice::Iter;
+
+use super::{Program, Instr, Val, fromBinary, read_imports, read_exports};
+use debuginfo::DebugInfo;
+use link::Relocations;
+
+pub const MAGIC: &[u8; 4] = b"VMOB";
+pub const VERSION: u32 = 1;
+
+pub const SECTION_CODE: u32 = 1;
+pub const SECTION_CONSTANTS: u32 = 2;
+pub const SECTION_SYMBOLS: u32 = 3;
+pub const SECTION_DEBUG: u32 = 4;
+pub const SECTION_HEAP: u32 = 5;
+pub const SECTION_RELOCATIONS: u32 = 6;
+
+pub trait ToBinary {
+    fn to_binary(&self, out: &mut Vec<u8>);
+}
+
+impl ToBinary for i32 {
+    fn to_binary(&self, out: &mut Vec<u8>) {
+        out.write_i32::<BigEndian>(*self).unwrap();
+    }
+}
+
+impl ToBinary for u32 {
+    fn to_binary(&self, out: &mut Vec<u8>) {
+        out.write_u32::<BigEndian>(*self).unwrap();
+    }
+}
+
+impl ToBinary for str { // a u32 byte length, then the UTF-8 bytes, the way read_name expects
+    fn to_binary(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).to_binary(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+fn values_to_binary(values: &[Val], out: &mut Vec<u8>) {
+    (values.len() as u32).to_binary(out);
+    for v in values.iter() {
+        v.to_binary(out);
+    }
+}
+
+fn read_values(bytes: &mut Iter<u8>) -> Vec<Val> {
+    let count = <u32 as fromBinary>::from_binary(bytes);
+    (0..count).map(|_| Val::from_binary(bytes)).collect()
+}
+
+fn section(kind: u32, payload: &[u8], out: &mut Vec<u8>) {
+    kind.to_binary(out);
+    (payload.len() as u32).to_binary(out);
+    out.extend_from_slice(payload);
+}
+
+pub fn write_object(program: &Program) -> Vec<u8> { // function to write program as a container, leaving out the optional sections it has nothing for
+    let mut sections: Vec<(u32, Vec<u8>)> = Vec::new();
+
+    let mut code = Vec::new();
+    (program.code.len() as u32).to_binary(&mut code);
+    for i in program.code.iter() {
+        i.to_binary(&mut code);
+    }
+    sections.push((SECTION_CODE, code));
+
+    if !program.constants.is_empty() {
+        let mut constants = Vec::new();
+        (program.constants.len() as u32).to_binary(&mut constants);
+        for object in program.constants.iter() {
+            values_to_binary(object, &mut constants);
+        }
+        sections.push((SECTION_CONSTANTS, constants));
+    }
+
+    let mut symbols = Vec::new();
+    (program.imports.len() as u32).to_binary(&mut symbols);
+    for name in program.imports.iter() {
+        name.to_binary(&mut symbols);
+    }
+    (program.exports.len() as u32).to_binary(&mut symbols);
+    for &(ref name, loc) in program.exports.iter() {
+        name.to_binary(&mut symbols);
+        loc.to_binary(&mut symbols);
+    }
+    sections.push((SECTION_SYMBOLS, symbols));
+
+    if let Some(ref d) = program.debug {
+        let mut debug = Vec::new();
+        d.to_binary(&mut debug);
+        sections.push((SECTION_DEBUG, debug));
+    }
+    if !program.heap.is_empty() {
+        let mut heap = Vec::new();
+        values_to_binary(&program.heap, &mut heap);
+        sections.push((SECTION_HEAP, heap));
+    }
+    if let Some(ref r) = program.relocations {
+        let mut relocations = Vec::new();
+        r.to_binary(&mut relocations);
+        sections.push((SECTION_RELOCATIONS, relocations));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    VERSION.to_binary(&mut out);
+    (sections.len() as u32).to_binary(&mut out);
+    for &(kind, ref payload) in sections.iter() {
+        section(kind, payload, &mut out);
+    }
+    out
+}
+
+pub fn is_container(bytes: &Iter<u8>) -> bool { // does the file start with the container's magic number?
+    bytes.as_slice().starts_with(MAGIC)
+}
+
+pub fn read_object(bytes: &mut Iter<u8>) -> Program { // function to read a container, the magic number still to come
+    for _ in 0..MAGIC.len() {
+        bytes.next();
+    }
+    let version = <u32 as fromBinary>::from_binary(bytes);
+    if version > VERSION {
+        panic!("Bad! Object format version {} is newer than this vm reads ({})", version, VERSION);
+    }
+    let count = <u32 as fromBinary>::from_binary(bytes);
+    let mut program = Program{code: Vec::new(), imports: Vec::new(), exports: Vec::new(),
+                              constants: Vec::new(), debug: None, heap: Vec::new(), relocations: None};
+    let mut seen: Vec<u32> = Vec::new();
+    for _ in 0..count {
+        let kind = <u32 as fromBinary>::from_binary(bytes);
+        let len = <u32 as fromBinary>::from_binary(bytes) as usize;
+        if bytes.len() < len {
+            panic!("Bad! Section {} runs past the end of the file", kind);
+        }
+        let payload: Vec<u8> = bytes.take(len).cloned().collect();
+        if seen.contains(&kind) {
+            panic!("Bad! Section {} appears twice", kind);
+        }
+        seen.push(kind);
+        let mut p = payload.iter();
+        match kind {
+            SECTION_CODE => {
+                let n = <u32 as fromBinary>::from_binary(&mut p);
+                program.code = (0..n).map(|_| Instr::from_binary(&mut p)).collect();
+            }
+            SECTION_CONSTANTS => {
+                let n = <u32 as fromBinary>::from_binary(&mut p);
+                program.constants = (0..n).map(|_| read_values(&mut p)).collect();
+            }
+            SECTION_SYMBOLS => {
+                program.imports = read_imports(&mut p);
+                program.exports = read_exports(&mut p);
+            }
+            SECTION_DEBUG => {
+                let d = DebugInfo::from_binary(&mut p);
+                if let Err(e) = d.check() {
+                    panic!("Bad! {}", e);
+                }
+                program.debug = Some(d);
+            }
+            SECTION_HEAP => program.heap = read_values(&mut p),
+            SECTION_RELOCATIONS => program.relocations = Some(Relocations::from_binary(&mut p)),
+            _ => continue // a section from a newer tool, skipped
+        }
+        if p.len() != 0 {
+            panic!("Bad! Section {} has {} bytes left over", kind, p.len());
+        }
+    }
+    if !seen.contains(&SECTION_CODE) {
+        panic!("Bad! Object file has no code section");
+    }
+    program
+}
@@ -0,0 +1,124 @@
+// Peephole optimizer that fuses common instruction sequences into superinstructions
+//   Push(Vi32(c)); Binary(Add)             => AddImm(c)
+//   Var(i); Var(j); Binary(b)              => VarVarBinary(i, j, b)
+//   Push(Vbool(true)); Push(Vloc(l)); Branch => Jump(l)
+// A sequence is only fused when nothing can jump into the middle of it and no source line of the
+// debug info starts inside it, and afterwards every code location (Push(Vloc) operands, handler
+// targets, exports and debug info) is renumbered.
+// --opt-level 2 first removes the Stores liveness.rs finds dead: along with the Push or Var
+// feeding one when nothing jumps between them, or else by turning the Store into a Pop.
+
+use std::collections::BTreeSet;
+
+use super::{Instr, Val, Binop, Program};
+use liveness;
+use stack;
+
+pub fn optimize(program: &Program, level: u32) -> Result<Program, String> { // function to return program rewritten for the given --opt-level, 0 leaves it alone
+    if level == 0 {
+        return Ok(program.clone());
+    }
+    let program = if level >= 2 { eliminate_dead_stores(program)? } else { program.clone() };
+    let code = &program.code;
+    let targets: BTreeSet<u32> = jump_targets(&program).union(&line_starts(&program)).cloned().collect();
+    let mut fused: Vec<Instr> = Vec::new();
+    let mut new_pc: Vec<u32> = Vec::with_capacity(code.len() + 1); // where each old instruction starts in the new program
+    let mut pc = 0;
+    while pc < code.len() {
+        let (i, width) = fuse(code, pc, &targets);
+        for _ in 0..width {
+            new_pc.push(fused.len() as u32);
+        }
+        fused.push(i);
+        pc += width;
+    }
+    new_pc.push(fused.len() as u32);
+    rebuild(&program, fused, &new_pc)
+}
+
+fn eliminate_dead_stores(program: &Program) -> Result<Program, String> { // function to drop every dead Store, and the Push or Var feeding it where it can
+    let dead = liveness::dead_stores(program);
+    let targets = jump_targets(program);
+    let lines = line_starts(program);
+    let bounded = stack::bounded(program, &stack::analyze(program, &[])); // else a Push may be what overflows, and has to stay
+    let old = &program.code;
+    let mut code: Vec<Instr> = Vec::new();
+    let mut new_pc: Vec<u32> = Vec::with_capacity(old.len() + 1);
+    let mut pc = 0;
+    while pc < old.len() {
+        let feeds = match old[pc] {
+            Instr::Push(_) if !bounded => false,
+            Instr::Push(_) | Instr::Var(_) => dead.contains(&(pc as u32 + 1)) && !targets.contains(&(pc as u32 + 1))
+                && (pc as u32..pc as u32 + 3).filter(|p| lines.contains(p)).count() <= 1, // all three end up at one pc
+            _ => false
+        };
+        if feeds { // both go, leaving the stack as it was
+            new_pc.push(code.len() as u32);
+            new_pc.push(code.len() as u32);
+            pc += 2;
+            continue;
+        }
+        new_pc.push(code.len() as u32);
+        code.push(if dead.contains(&(pc as u32)) { Instr::Pop } else { old[pc].clone() });
+        pc += 1;
+    }
+    new_pc.push(code.len() as u32);
+    rebuild(program, code, &new_pc)
+}
+
+fn rebuild(program: &Program, mut code: Vec<Instr>, new_pc: &[u32]) -> Result<Program, String> { // function to give program new code, where old pc l is now at new_pc[l]
+    let remap = |l: u32| -> u32 {
+        match new_pc.get(l as usize) {
+            Some(n) => *n,
+            None => l // not a code location, leave it be
+        }
+    };
+    for i in code.iter_mut() {
+        match i {
+            Instr::Push(Val::Vloc(l)) => *l = remap(*l),
+            Instr::Jump(l) => *l = remap(*l),
+            Instr::PushHandler(l) => *l = remap(*l),
+            _ => ()
+        }
+    }
+    let exports = program.exports.iter().map(|(name, l)| (name.clone(), remap(*l))).collect();
+    let debug = match program.debug {
+        Some(ref d) => Some(d.remap(remap)?),
+        None => None
+    };
+    Ok(Program{code, imports: program.imports.clone(), exports, constants: program.constants.clone(),
+               debug, heap: program.heap.clone(),
+               relocations: None}) // the optimized program is only ever run, never linked
+}
+
+fn line_starts(program: &Program) -> BTreeSet<u32> { // function to collect the pcs where the debug info starts a line entry
+    program.debug.as_ref().map_or(BTreeSet::new(), |d| d.lines.iter().map(|l| l.pc).collect())
+}
+
+fn jump_targets(program: &Program) -> BTreeSet<u32> { // function to collect every location control can arrive at other than by falling through
+    let mut targets = BTreeSet::new();
+    for (pc, i) in program.code.iter().enumerate() {
+        match i {
+            Instr::Push(Val::Vloc(l)) => { targets.insert(*l); }
+            Instr::PushHandler(l) => { targets.insert(*l); }
+            Instr::Call => { targets.insert(pc as u32 + 1); } // the return address
+            _ => ()
+        }
+    }
+    for (_, l) in program.exports.iter() {
+        targets.insert(*l);
+    }
+    targets
+}
+
+fn fuse(code: &[Instr], pc: usize, targets: &BTreeSet<u32>) -> (Instr, usize) { // function to pick the instruction to emit at pc and how many old ones it covers
+    let free = |n: usize| -> bool { // none of the n instructions after pc can be jumped to
+        pc + n < code.len() && (1..=n).all(|k| !targets.contains(&((pc + k) as u32)))
+    };
+    match (&code[pc], code.get(pc + 1), code.get(pc + 2)) {
+        (Instr::Push(Val::Vi32(c)), Some(Instr::Binary(Binop::Add)), _) if free(1) => (Instr::AddImm(*c), 2),
+        (Instr::Var(i), Some(Instr::Var(j)), Some(Instr::Binary(b))) if free(2) => (Instr::VarVarBinary(*i, *j, b.clone()), 3),
+        (Instr::Push(Val::Vbool(true)), Some(Instr::Push(Val::Vloc(l))), Some(Instr::Branch)) if free(2) => (Instr::Jump(*l), 3),
+        (i, _, _) => (i.clone(), 1)
+    }
+}
@@ -0,0 +1,324 @@
+// Execution profiler for `vm file.o --profile[=report.json]`
+// Runs the program in the interpreter, counting how often each pc executes. A function is
+// entered by a Call and left when its frame record comes off the control stack (Ret, or a
+// handler unwinding past it), and gets the instructions and wall time spent inside it, both
+// on its own (self) and including what it called (total). Recursive calls only add to a
+// function's total once, at the outermost activation. The report goes to stderr so the
+// program's own output is unchanged, and the JSON version to the given file.
+// With --folded[=stacks.txt] it also writes collapsed stacks (`L0;L4;L10;L10 1234`) for
+// flamegraph tools, weighted by instructions executed or, with --sample=US, by how many
+// samples taken every US microseconds of wall time landed on each stack.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use super::{State, Program, Instr, Val, fetch, step};
+use debuginfo::function_name;
+use isa;
+
+#[derive(Debug,Clone,Default)]
+pub struct FunctionStats {
+    pub calls: u64,              //Times the function was entered
+    pub self_instructions: u64,  //Instructions run in the function itself
+    pub total_instructions: u64, //Instructions run in the function and everything it called
+    pub self_time: Duration,
+    pub total_time: Duration,
+    active: u32                  //Activations currently on the stack, to count recursion once
+}
+
+struct Activation {
+    entry: u32,               //The pc the function was called at
+    frames: usize,            //The control stack height while the function runs
+    start: Instant,
+    start_instructions: u64,
+    child_instructions: u64,  //Instructions spent in direct callees
+    child_time: Duration
+}
+
+#[derive(Debug,Clone,Default)]
+pub struct ProfileOptions {
+    pub report: bool,                   //Print the text report to stderr
+    pub json: Option<String>,           //Write the JSON report to this file
+    pub folded: Option<Option<String>>, //Write collapsed stacks to this file, or to stderr
+    pub sample: Option<Duration>,       //Weight stacks by wall time samples at this interval rather than instructions
+    pub counts_only: bool               //Leave wall times out of the reports, so they only depend on the program
+}
+
+pub struct Profiler {
+    pub counts: Vec<u64>,         //How many times each pc executed
+    pub instructions: u64,        //Instructions executed in all
+    pub max_stack: usize,         //Deepest the value stack got
+    pub heap_high_water: usize,   //Most heap slots in use
+    pub functions: BTreeMap<u32, FunctionStats>, //Per function, by entry pc
+    pub elapsed: Duration,        //Wall time for the whole run
+    pub stacks: HashMap<Vec<u32>, u64>, //Instructions or samples for each stack of function entries, outermost first
+    active: Vec<Activation>,      //The functions being run, innermost last
+    path: Vec<u32>,               //The entries of the active functions, the key into stacks
+    unflushed: u64,               //Instructions run on path since it last changed
+    sample: Option<Duration>,
+    next_sample: Option<Instant>
+}
+
+impl Profiler {
+    pub fn new(program: &[Instr]) -> Profiler {
+        Profiler{counts: vec![0; program.len()], instructions: 0, max_stack: 0, heap_high_water: 0,
+                 functions: BTreeMap::new(), elapsed: Duration::new(0, 0), stacks: HashMap::new(), active: Vec::new(),
+                 path: Vec::new(), unflushed: 0, sample: None, next_sample: None}
+    }
+
+    pub fn sample_every(&mut self, interval: Duration) { // function to weight stacks by samples instead of instructions
+        self.sample = Some(interval);
+        self.next_sample = Some(Instant::now() + interval);
+    }
+
+    fn flush(&mut self) { // function to charge the instructions run since the stack last changed to it
+        if self.unflushed > 0 {
+            *self.stacks.entry(self.path.clone()).or_insert(0) += self.unflushed;
+            self.unflushed = 0;
+        }
+    }
+
+    fn enter(&mut self, entry: u32, frames: usize) { // function to start timing a call to entry
+        self.flush();
+        self.path.push(entry);
+        let stats = self.functions.entry(entry).or_default();
+        stats.calls += 1;
+        stats.active += 1;
+        self.active.push(Activation{entry, frames, start: Instant::now(),
+                                    start_instructions: self.instructions, child_instructions: 0,
+                                    child_time: Duration::new(0, 0)});
+    }
+
+    fn leave(&mut self) { // function to charge the innermost activation to its function and its caller
+        self.flush();
+        self.path.pop();
+        let a = match self.active.pop() {
+            Some(a) => a,
+            None => return
+        };
+        let time = a.start.elapsed();
+        let instructions = self.instructions - a.start_instructions;
+        let stats = self.functions.get_mut(&a.entry).unwrap();
+        stats.active -= 1;
+        stats.self_instructions += instructions - a.child_instructions;
+        stats.self_time += time.checked_sub(a.child_time).unwrap_or(Duration::new(0, 0));
+        if stats.active == 0 { // only the outermost activation of a recursive function counts towards its total
+            stats.total_instructions += instructions;
+            stats.total_time += time;
+        }
+        if let Some(caller) = self.active.last_mut() {
+            caller.child_instructions += instructions;
+            caller.child_time += time;
+        }
+    }
+
+    fn note(&mut self, pc: u32, callee: Option<u32>, s: &State) { // function to record one executed instruction
+        self.instructions += 1;
+        self.counts[pc as usize] += 1;
+        match self.next_sample {
+            None => self.unflushed += 1,
+            Some(next) => {
+                if self.instructions.is_multiple_of(64) && Instant::now() >= next { // only look at the clock now and then
+                    *self.stacks.entry(self.path.clone()).or_insert(0) += 1;
+                    self.next_sample = Some(next + self.sample.unwrap());
+                }
+            }
+        }
+        if s.stack.len() > self.max_stack {
+            self.max_stack = s.stack.len();
+        }
+        if s.heap.len() > self.heap_high_water {
+            self.heap_high_water = s.heap.len();
+        }
+        while self.active.len() > 1 && self.active.last().unwrap().frames > s.control.len() { // returned or unwound out of it
+            self.leave();
+        }
+        if let Some(target) = callee {
+            if s.pc == target { // the Call went through
+                self.enter(target, s.control.len());
+            }
+        }
+    }
+}
+
+pub fn exec_profile(program: &Program, s: &mut State, options: &ProfileOptions) { // function to run the program like exec does, then write out the profile
+    let mut p = Profiler::new(&program.code);
+    if let Some(interval) = options.sample {
+        p.sample_every(interval);
+    }
+    let started = Instant::now();
+    p.enter(s.pc, 0); // everything before the first Call belongs to the entry point
+    'mainloop: loop{
+        if s.halt { break 'mainloop }
+        let pc = s.pc;
+        let i = match fetch(&program.code, s){
+            Ok(i) => i,
+            Err(e) => panic!("exec: {}", e)
+        };
+        let callee = match (i, s.stack.last().map(|w| w.val())) {
+            (Instr::Call, Some(Val::Vloc(target))) => Some(target),
+            _ => None
+        };
+        if let Err(e) = step(i, s) {
+            panic!("{}", e);
+        }
+        p.note(pc, callee, s);
+    }
+    while !p.active.is_empty() {
+        p.leave();
+    }
+    p.elapsed = started.elapsed();
+
+    let result = s.stack.pop().unwrap();
+    print!("{:?}",result);
+    if options.report {
+        eprint!("{}", report(&p, program, options.counts_only));
+    }
+    if let Some(ref path) = options.json {
+        fs::write(path, to_json(&p, program, options.counts_only)).expect("Could not write the profile");
+    }
+    match options.folded {
+        Some(Some(ref path)) => fs::write(path, folded(&p, program)).expect("Could not write the folded stacks"),
+        Some(None) => eprint!("{}", folded(&p, program)),
+        None => ()
+    }
+}
+
+fn opcode_counts(p: &Profiler, program: &Program) -> Vec<(&'static str, u64)> { // function to total the per-pc counts by opcode, most executed first
+    let mut by_opcode: BTreeMap<&'static str, u64> = BTreeMap::new();
+    for (pc, n) in p.counts.iter().enumerate() {
+        if *n > 0 {
+            *by_opcode.entry(isa::opcode(&program.code[pc]).name).or_insert(0) += *n;
+        }
+    }
+    let mut sorted: Vec<(&'static str, u64)> = by_opcode.into_iter().collect();
+    sorted.sort_by_key(|a| Reverse(a.1));
+    sorted
+}
+
+fn functions_by_self(p: &Profiler) -> Vec<(&u32, &FunctionStats)> { // the functions, most self instructions first
+    let mut sorted: Vec<(&u32, &FunctionStats)> = p.functions.iter().collect();
+    sorted.sort_by_key(|a| Reverse(a.1.self_instructions));
+    sorted
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { n as f64 * 100.0 / total as f64 }
+}
+
+pub fn report(p: &Profiler, program: &Program, counts_only: bool) -> String { // function to lay the profile out as text, without the times if counts_only
+    let mut out = String::new();
+    writeln!(out, "\n== profile ==").unwrap();
+    if counts_only {
+        writeln!(out, "{} instructions, max stack depth {}, heap high-water mark {}",
+                 p.instructions, p.max_stack, p.heap_high_water).unwrap();
+    }
+    else {
+        writeln!(out, "{} instructions in {:.3} ms, max stack depth {}, heap high-water mark {}",
+                 p.instructions, ms(p.elapsed), p.max_stack, p.heap_high_water).unwrap();
+    }
+
+    writeln!(out, "\n-- functions, by self instructions --").unwrap();
+    write!(out, "{:<16} {:>8} {:>14} {:>7} {:>14}", "function", "calls", "self instrs", "self%", "total instrs").unwrap();
+    if !counts_only {
+        write!(out, " {:>10} {:>10}", "self ms", "total ms").unwrap();
+    }
+    out.push('\n');
+    for (entry, f) in functions_by_self(p) {
+        write!(out, "{:<16} {:>8} {:>14} {:>6.2}% {:>14}",
+               function_name(program, *entry), f.calls, f.self_instructions, percent(f.self_instructions, p.instructions),
+               f.total_instructions).unwrap();
+        if !counts_only {
+            write!(out, " {:>10.3} {:>10.3}", ms(f.self_time), ms(f.total_time)).unwrap();
+        }
+        out.push('\n');
+    }
+
+    writeln!(out, "\n-- opcodes --").unwrap();
+    for (name, n) in opcode_counts(p, program) {
+        writeln!(out, "{:<16} {:>14} {:>6.2}%", name, n, percent(n, p.instructions)).unwrap();
+    }
+
+    writeln!(out, "\n-- hottest instructions --").unwrap();
+    let mut pcs: Vec<usize> = (0..p.counts.len()).filter(|pc| p.counts[*pc] > 0).collect();
+    pcs.sort_by(|a, b| p.counts[*b].cmp(&p.counts[*a]).then(a.cmp(b)));
+    for pc in pcs.into_iter().take(20) {
+        writeln!(out, "{:>6} {:>14} {:>6.2}%  {:?}", pc, p.counts[pc], percent(p.counts[pc], p.instructions), program.code[pc]).unwrap();
+    }
+    out
+}
+
+pub fn folded(p: &Profiler, program: &Program) -> String { // function to write one `outer;...;inner weight` line per stack
+    let mut lines: Vec<String> = p.stacks.iter()
+        .filter(|(_, n)| **n > 0)
+        .map(|(path, n)| {
+            let names: Vec<String> = path.iter().map(|entry| function_name(program, *entry)).collect();
+            format!("{} {}", names.join(";"), n)
+        })
+        .collect();
+    lines.sort();
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+fn json_string(s: &str) -> String { // function to quote s as a JSON string
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn to_json(p: &Profiler, program: &Program, counts_only: bool) -> String { // function to write the profile as JSON, times in nanoseconds unless counts_only
+    let mut out = String::new();
+    write!(out, "{{\"instructions\":{}", p.instructions).unwrap();
+    if !counts_only {
+        write!(out, ",\"wall_ns\":{}", p.elapsed.as_nanos()).unwrap();
+    }
+    write!(out, ",\"max_stack\":{},\"heap_high_water\":{}", p.max_stack, p.heap_high_water).unwrap();
+
+    out.push_str(",\"functions\":[");
+    for (k, (entry, f)) in functions_by_self(p).into_iter().enumerate() {
+        if k > 0 { out.push(','); }
+        write!(out, "{{\"name\":{},\"entry\":{},\"calls\":{},\"self_instructions\":{},\"total_instructions\":{}",
+               json_string(&function_name(program, *entry)), entry, f.calls, f.self_instructions, f.total_instructions).unwrap();
+        if !counts_only {
+            write!(out, ",\"self_ns\":{},\"total_ns\":{}", f.self_time.as_nanos(), f.total_time.as_nanos()).unwrap();
+        }
+        out.push('}');
+    }
+
+    out.push_str("],\"opcodes\":{");
+    for (k, (name, n)) in opcode_counts(p, program).into_iter().enumerate() {
+        if k > 0 { out.push(','); }
+        write!(out, "{}:{}", json_string(name), n).unwrap();
+    }
+
+    out.push_str("},\"pcs\":[");
+    let mut first = true;
+    for (pc, n) in p.counts.iter().enumerate() {
+        if *n == 0 { continue; }
+        if !first { out.push(','); }
+        first = false;
+        write!(out, "{{\"pc\":{},\"count\":{},\"instr\":{}}}", pc, n, json_string(&format!("{:?}", program.code[pc]))).unwrap();
+    }
+    out.push_str("]}\n");
+    out
+}
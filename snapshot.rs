@@ -0,0 +1,145 @@
+// Machine snapshots for `vm file.o --checkpoint=file.snap [--every=N]` and `--resume=file.snap`
+// A snapshot holds everything needed to carry on running: pc, fp, the halt flag, the value stack,
+// the heap, the control stack and the installed handlers, along with a hash of the program it was
+// taken from so it is never resumed against different code or data. Natives and input are not saved. The
+// resuming process registers its own natives, and the program reads input from wherever that
+// process's input comes from. All numbers are big endian, like object files:
+//   "VMSS"  version:u32  program hash:u64  pc:u32  fp:u32  halt:u8
+//   stack:    count:u32, then a Word's bits:u64 each
+//   heap:     count:u32, then a Word's bits:u64 each
+//   control:  count:u32, then fp:u32 has_ret:u8 ret:u32 each
+//   handlers: count:u32, then target:u32 fp:u32 sp:u32 frames:u32 each
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs;
+use std::io;
+
+use super::{State, Program, Frame, Handler, Word, fetch, step};
+
+pub const MAGIC: &[u8; 4] = b"VMSS";
+pub const VERSION: u32 = 1;
+pub const CHECKPOINT_EVERY: u64 = 1000000; //Instructions between checkpoints if --every isn't given
+
+pub fn program_hash(program: &Program) -> u64 { // function to fingerprint the program a snapshot belongs to, 64-bit FNV-1a over its instructions, imports, exports, constants and initial heap
+    let text = format!("{:?}{:?}{:?}{:?}{:?}", program.code, program.imports, program.exports, program.constants, program.heap);
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in text.bytes() {
+        h = (h ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+pub fn save(s: &State, program: &Program) -> Vec<u8> { // function to write the machine out as a snapshot
+    let mut out: Vec<u8> = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.write_u32::<BigEndian>(VERSION).unwrap();
+    out.write_u64::<BigEndian>(program_hash(program)).unwrap();
+    out.write_u32::<BigEndian>(s.pc).unwrap();
+    out.write_u32::<BigEndian>(s.fp).unwrap();
+    out.write_u8(s.halt as u8).unwrap();
+    for words in [&s.stack, &s.heap].iter() {
+        out.write_u32::<BigEndian>(words.len() as u32).unwrap();
+        for w in words.iter() {
+            out.write_u64::<BigEndian>(w.bits()).unwrap();
+        }
+    }
+    out.write_u32::<BigEndian>(s.control.len() as u32).unwrap();
+    for f in s.control.iter() {
+        out.write_u32::<BigEndian>(f.fp).unwrap();
+        out.write_u8(f.ret.is_some() as u8).unwrap();
+        out.write_u32::<BigEndian>(f.ret.unwrap_or(0)).unwrap();
+    }
+    out.write_u32::<BigEndian>(s.handlers.len() as u32).unwrap();
+    for h in s.handlers.iter() {
+        out.write_u32::<BigEndian>(h.target).unwrap();
+        out.write_u32::<BigEndian>(h.fp).unwrap();
+        out.write_u32::<BigEndian>(h.sp as u32).unwrap();
+        out.write_u32::<BigEndian>(h.frames as u32).unwrap();
+    }
+    out
+}
+
+fn read_words(bytes: &mut &[u8]) -> io::Result<Option<Vec<Word>>> { // a count and that many Words, None if a tag is bad
+    let n = bytes.read_u32::<BigEndian>()?;
+    let mut words = Vec::new();
+    for _ in 0..n {
+        match Word::from_bits(bytes.read_u64::<BigEndian>()?) {
+            Some(w) => words.push(w),
+            None => return Ok(None)
+        }
+    }
+    Ok(Some(words))
+}
+
+pub fn restore(s: &mut State, bytes: &[u8], program: &Program) -> Result<(), String> { // function to load a snapshot of program into s, leaving its natives and input alone
+    let mut bytes = bytes;
+    if bytes.len() < 4 || &bytes[..4] != MAGIC {
+        return Err("Not a snapshot file".to_string());
+    }
+    bytes = &bytes[4..];
+    let truncated = |_: io::Error| "Snapshot is truncated".to_string();
+    let version = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+    if version != VERSION {
+        return Err(format!("Snapshot version {} is not supported, this vm reads version {}", version, VERSION));
+    }
+    if bytes.read_u64::<BigEndian>().map_err(truncated)? != program_hash(program) {
+        return Err("Snapshot was taken from a different program".to_string());
+    }
+    let pc = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+    let fp = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+    let halt = bytes.read_u8().map_err(truncated)? != 0;
+    let stack = read_words(&mut bytes).map_err(truncated)?.ok_or("Snapshot stack holds a bad value")?;
+    let heap = read_words(&mut bytes).map_err(truncated)?.ok_or("Snapshot heap holds a bad value")?;
+    let mut control = Vec::new();
+    for _ in 0..bytes.read_u32::<BigEndian>().map_err(truncated)? {
+        let fp = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+        let has_ret = bytes.read_u8().map_err(truncated)? != 0;
+        let ret = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+        control.push(Frame{fp, ret: if has_ret { Some(ret) } else { None }});
+    }
+    let mut handlers = Vec::new();
+    for _ in 0..bytes.read_u32::<BigEndian>().map_err(truncated)? {
+        let target = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+        let fp = bytes.read_u32::<BigEndian>().map_err(truncated)?;
+        let sp = bytes.read_u32::<BigEndian>().map_err(truncated)? as usize;
+        let frames = bytes.read_u32::<BigEndian>().map_err(truncated)? as usize;
+        handlers.push(Handler{target, fp, sp, frames});
+    }
+    if !bytes.is_empty() {
+        return Err("Snapshot has trailing bytes".to_string());
+    }
+    s.pc = pc;
+    s.fp = fp;
+    s.halt = halt;
+    s.stack = stack;
+    s.heap = heap;
+    s.control = control;
+    s.handlers = handlers;
+    Ok(())
+}
+
+fn write_snapshot(path: &str, s: &State, program: &Program) { // written to the side and renamed, so a crash mid-write leaves the last checkpoint whole
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, save(s, program)).expect("Could not write the snapshot");
+    fs::rename(&tmp, path).expect("Could not write the snapshot");
+}
+
+pub fn exec_checkpointed(program: &Program, s: &mut State, path: &str, every: u64) { // function to run the program like exec does, saving the machine to path every so many instructions
+    let mut executed: u64 = 0;
+    'mainloop: loop{
+        if s.halt { break 'mainloop }
+        if executed.is_multiple_of(every) {
+            write_snapshot(path, s, program);
+        }
+        let i = match fetch(&program.code, s){
+            Ok(i) => i,
+            Err(e) => panic!("exec: {}", e)
+        };
+        if let Err(e) = step(i, s) { // the last checkpoint is at most every instructions before this, --resume it to reproduce
+            panic!("{} (last checkpoint in {})", e, path);
+        }
+        executed += 1;
+    }
+    let result = s.stack.pop().unwrap();
+    print!("{:?}",result);
+}
@@ -0,0 +1,239 @@
+// Static stack analysis, for `vm file.o --stack`
+// Every function in the control-flow graph is walked with the stack effects from the isa table,
+// counting heights from the function's entry, where its arguments, saved fp and return address are
+// already on the stack. A Call comes back with the values of the frame its SetFrame opened replaced
+// by the result, a handler starts one above the height at its PushHandler, and Ret, Halt and Throw
+// end a path. Each function gets its highest operand stack, the frame slots its Var and Store reach
+// and the most values a SetFrame of it passes, and a block reached at two different heights is
+// reported. Through the call graph this gives the deepest stack a call of the function can use,
+// unless it can recurse, which is reported too.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use super::{Program, Instr, Native};
+use cfg::{self, Cfg, EdgeKind};
+use isa::{self, Effect};
+
+pub const STACK_LIMIT: u32 = 1025; //What Push lets the stack grow to
+
+#[derive(Debug,Clone)]
+pub struct CallSite {
+    pub pc: u32,
+    pub callee: u32,     //The entry of the function called
+    pub base: u32,       //The caller's height once the Call has pushed its return address, the callee's entry height
+    pub fp: Option<i64>  //Where the callee's fp is relative to its entry height, if a SetFrame opened its frame
+}
+
+#[derive(Debug,Clone)]
+pub struct FunctionStack {
+    pub entry: u32,
+    pub name: String,
+    pub max_height: u32,        //The most values the function has above its entry height
+    pub slots: u32,             //Frame slots reached by Var and Store, one past the highest
+    pub args: u32,              //The most values a SetFrame passes to a callee
+    pub calls: Vec<CallSite>,   //The calls whose callee is known
+    pub indirect: bool,         //Makes calls whose target isn't known, which depth leaves out
+    pub recursive: bool,        //Can call itself, directly or not
+    pub depth: Option<u32>,     //The deepest the stack gets above the entry height in a call of it, None if recursive
+    pub heights: BTreeMap<u32, i64>, //The height before each instruction walked
+    pub problems: Vec<String>
+}
+
+struct Walk<'a> {
+    program: &'a Program,
+    natives: &'a [Native],
+    cfg: &'a Cfg,
+    heights: BTreeMap<usize, i64>, //Entry height of each block reached
+    work: Vec<usize>,
+    out: FunctionStack
+}
+
+impl<'a> Walk<'a> {
+    fn reach(&mut self, block: usize, h: i64) { // function to arrive at block with height h, checking it against earlier arrivals
+        match self.heights.get(&block).cloned() {
+            Some(old) if old != h => {
+                let problem = format!("pc {} is reached with stack heights {} and {}", self.cfg.blocks[block].start, old, h);
+                if !self.out.problems.contains(&problem) {
+                    self.out.problems.push(problem);
+                }
+            }
+            Some(_) => (),
+            None => {
+                self.heights.insert(block, h);
+                self.work.push(block);
+            }
+        }
+    }
+
+    fn block(&mut self, k: usize) { // function to walk block k from its entry height
+        let (program, cfg) = (self.program, self.cfg);
+        let b = &cfg.blocks[k];
+        let mut h = self.heights[&k];
+        let mut returns: Option<i64> = None; // the height a Call comes back at, once a SetFrame has opened its frame
+        let mut handlers: Vec<(u32, i64)> = Vec::new();
+        for pc in b.start..b.end {
+            let i = &program.code[pc as usize];
+            self.out.heights.insert(pc, h);
+            match *i {
+                Instr::Var(x) | Instr::Store(x) => self.out.slots = self.out.slots.max(x + 1),
+                Instr::SetFrame(n) => {
+                    self.out.args = self.out.args.max(n);
+                    returns = Some(h - n as i64 + 1);
+                }
+                Instr::PushHandler(l) => handlers.push((l, h + 1)),
+                _ => ()
+            }
+            let (pops, pushes) = match isa::opcode(i).effect {
+                Effect::Fixed(pops, pushes) => (pops, pushes),
+                Effect::Return => (1, 0),
+                Effect::Native => {
+                    let x = if let Instr::CallNative(x) = *i { x } else { unreachable!() };
+                    match self.natives.get(x as usize) {
+                        Some(n) => (n.arity as u32, 1),
+                        None => {
+                            self.out.problems.push(format!("pc {}: CallNative({}) has no native, so its arity is unknown", pc, x));
+                            return;
+                        }
+                    }
+                }
+            };
+            if h < pops as i64 {
+                self.out.problems.push(format!("pc {}: {} pops below the function's entry height", pc, i));
+                return;
+            }
+            h = h - pops as i64 + pushes as i64;
+            self.out.max_height = self.out.max_height.max(h as u32);
+        }
+        for (l, hh) in handlers.into_iter() {
+            if let Some(t) = cfg.block_at(l) {
+                self.reach(t, hh);
+            }
+        }
+        let last = b.end - 1;
+        if let Instr::Call = program.code[last as usize] {
+            match cfg.calls.iter().find(|c| c.0 == last).and_then(|c| c.1) {
+                Some(callee) => self.out.calls.push(CallSite{pc: last, callee, base: h as u32, fp: returns.map(|r| r - 1 - h)}),
+                None => self.out.indirect = true
+            }
+        }
+        for e in b.succs.iter() {
+            match e.kind {
+                EdgeKind::Fallthrough | EdgeKind::Taken | EdgeKind::NotTaken | EdgeKind::Jump => self.reach(e.to, h),
+                EdgeKind::AfterCall => match returns {
+                    Some(r) => self.reach(e.to, r),
+                    None => self.out.problems.push(format!("pc {}: Call without a SetFrame, so where it returns to is unknown", last))
+                },
+                EdgeKind::Call | EdgeKind::Return | EdgeKind::Handler => ()
+            }
+        }
+    }
+}
+
+fn walk(program: &Program, natives: &[Native], cfg: &Cfg, f: &cfg::Function) -> FunctionStack { // function to find the stack use of one function
+    let mut w = Walk{program, natives, cfg, heights: BTreeMap::new(), work: Vec::new(),
+                     out: FunctionStack{entry: f.entry, name: f.name.clone(), max_height: 0, slots: 0, args: 0, calls: Vec::new(),
+                                        indirect: false, recursive: false, depth: None, heights: BTreeMap::new(), problems: Vec::new()}};
+    w.reach(f.blocks[0], 0);
+    while let Some(k) = w.work.pop() {
+        w.block(k);
+    }
+    w.out
+}
+
+fn depth(functions: &[FunctionStack], k: usize, memo: &mut Vec<Option<Option<u32>>>) -> Option<u32> { // the deepest stack under functions[k], None if it can recurse
+    if let Some(d) = memo[k] {
+        return d;
+    }
+    memo[k] = Some(None); // until it is known, a call back here is recursion
+    let mut d = Some(functions[k].max_height);
+    for call in functions[k].calls.iter() {
+        let c = match functions.iter().position(|f| f.entry == call.callee) {
+            Some(c) => c,
+            None => continue
+        };
+        d = match (d, depth(functions, c, memo)) {
+            (Some(d), Some(cd)) => Some(d.max(call.base + cd)),
+            _ => None
+        };
+    }
+    memo[k] = Some(d);
+    d
+}
+
+fn reaches(functions: &[FunctionStack], from: usize, to: u32) -> bool { // can functions[from] call the function at to, directly or not?
+    let mut seen: BTreeSet<usize> = BTreeSet::new();
+    let mut work = vec![from];
+    while let Some(k) = work.pop() {
+        for call in functions[k].calls.iter() {
+            if call.callee == to {
+                return true;
+            }
+            if let Some(c) = functions.iter().position(|f| f.entry == call.callee) {
+                if seen.insert(c) {
+                    work.push(c);
+                }
+            }
+        }
+    }
+    false
+}
+
+pub fn analyze(program: &Program, natives: &[Native]) -> Vec<FunctionStack> { // function to find the stack use of every function, natives being what CallNative indexes
+    analyze_cfg(program, natives, &cfg::build(program))
+}
+
+pub fn analyze_cfg(program: &Program, natives: &[Native], cfg: &Cfg) -> Vec<FunctionStack> { // the same, for a graph already built, one result per cfg.functions
+    let mut functions: Vec<FunctionStack> = cfg.functions.iter().map(|f| walk(program, natives, cfg, f)).collect();
+    for k in 0..functions.len() {
+        let entry = functions[k].entry;
+        functions[k].recursive = reaches(&functions, k, entry);
+    }
+    let mut memo = vec![None; functions.len()];
+    for k in 0..functions.len() {
+        functions[k].depth = depth(&functions, k, &mut memo);
+    }
+    functions
+}
+
+pub fn bounded(program: &Program, functions: &[FunctionStack]) -> bool { // function to tell whether no run from pc 0 can take the stack past STACK_LIMIT
+    program.exports.is_empty() && functions.iter().all(|f| !f.indirect && f.problems.is_empty())
+        && functions.iter().find(|f| f.entry == 0).and_then(|f| f.depth).is_some_and(|d| d <= STACK_LIMIT)
+}
+
+pub fn report(functions: &[FunctionStack]) -> String { // function to lay the analysis out as a table, then what it found wrong
+    let mut out = String::new();
+    writeln!(out, "{:<20} {:>6} {:>6} {:>6} {:>6}  deepest", "function", "entry", "stack", "slots", "args").unwrap();
+    for (k, f) in functions.iter().enumerate() {
+        let deepest = match f.depth {
+            Some(d) if f.indirect => format!("{}+ (calls through pointers)", d),
+            Some(d) => d.to_string(),
+            None if f.recursive => "unbounded (recursive)".to_string(),
+            None => match functions.iter().find(|g| g.recursive && reaches(functions, k, g.entry)) {
+                Some(g) => format!("unbounded (calls recursive {})", g.name),
+                None => "unbounded".to_string()
+            }
+        };
+        writeln!(out, "{:<20} {:>6} {:>6} {:>6} {:>6}  {}", f.name, f.entry, f.max_height, f.slots, f.args, deepest).unwrap();
+    }
+    for f in functions.iter() {
+        if f.recursive {
+            let back = |c: &&CallSite| c.callee == f.entry || functions.iter().position(|g| g.entry == c.callee).is_some_and(|g| reaches(functions, g, f.entry));
+            match f.calls.iter().find(back) {
+                Some(c) => writeln!(out, "{} is recursive, through the call at pc {}", f.name, c.pc).unwrap(),
+                None => writeln!(out, "{} is recursive", f.name).unwrap()
+            }
+        }
+    }
+    if let Some(d) = functions.iter().find(|f| f.entry == 0).and_then(|f| f.depth) {
+        if d > STACK_LIMIT {
+            writeln!(out, "the program can need {} stack values, more than the {} the stack holds", d, STACK_LIMIT).unwrap();
+        }
+    }
+    for f in functions.iter() {
+        for p in f.problems.iter() {
+            writeln!(out, "{}: {}", f.name, p).unwrap();
+        }
+    }
+    out
+}
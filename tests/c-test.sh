@@ -0,0 +1,34 @@
+#!/bin/bash
+# Compiles every program to C, builds it with the system C compiler and checks it
+# prints the same result as the interpreter. Programs run through --call are skipped.
+
+ERR=0
+INPUTS=`ls *.o`
+CC=${CC:-cc}
+
+for f in $INPUTS;
+do
+    if [ -f "${f%.o}.args" ]; then
+	printf "%-10s %10s\n" $f "skipped"
+	continue
+    fi
+    IN=/dev/null
+    if [ -f "${f%.o}.in" ]; then
+	IN="${f%.o}.in"
+    fi
+    ../target/release/vm $f < $IN > "${f%.o}.interp" 2>/dev/null
+    if ! ../target/release/vm $f --compile-c "${f%.o}.c" 2>/dev/null || ! $CC -O2 -o "${f%.o}.native" "${f%.o}.c"; then
+	printf "%-10s %10s\n" $f "ERROR, could not compile to C"
+	ERR=1
+	continue
+    fi
+    ./"${f%.o}.native" < $IN > "${f%.o}.cout" 2>/dev/null
+    if ! diff -q "${f%.o}.interp" "${f%.o}.cout" &>/dev/null; then
+	printf "%-10s %10s\n" $f "ERROR, C and interpreter differ"
+	ERR=1
+    else
+	printf "%-10s %10s\n" $f "passed"
+    fi
+done
+
+exit $ERR
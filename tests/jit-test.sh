@@ -0,0 +1,28 @@
+#!/bin/bash
+# Runs every program both interpreted and with every function compiled on its first call,
+# and checks the two agree. Needs a vm built with `cargo build --release --features jit`.
+
+ERR=0
+INPUTS=`ls *.o`
+
+for f in $INPUTS;
+do
+    ARGS=""
+    if [ -f "${f%.o}.args" ]; then
+	ARGS=`cat "${f%.o}.args"`
+    fi
+    IN=/dev/null
+    if [ -f "${f%.o}.in" ]; then
+	IN="${f%.o}.in"
+    fi
+    ../target/release/vm $f $ARGS < $IN > "${f%.o}.interp" 2>/dev/null
+    ../target/release/vm $f --jit=0 $ARGS < $IN > "${f%.o}.jit" 2>/dev/null
+    if ! diff -q "${f%.o}.interp" "${f%.o}.jit" &>/dev/null; then
+	printf "%-10s %10s\n" $f "ERROR, jit and interpreter differ"
+	ERR=1
+    else
+	printf "%-10s %10s\n" $f "passed"
+    fi
+done
+
+exit $ERR
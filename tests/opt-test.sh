@@ -0,0 +1,44 @@
+#!/bin/bash
+# Runs every program with and without the optimizer, at each level, and checks they all agree.
+# Modes whose output names pcs (--debug, --resume, --disassemble, --cfg) are skipped, the
+# optimizer moves code around. Where it moves pcs a report names, name.expected.opt holds what
+# both levels should print instead.
+
+ERR=0
+INPUTS=`ls *.o`
+
+for f in $INPUTS;
+do
+    ARGS=""
+    if [ -f "${f%.o}.args" ]; then
+	ARGS=`cat "${f%.o}.args"`
+    fi
+    if [[ "$ARGS" =~ --debug|--resume|--disassemble|--cfg ]]; then
+	printf "%-10s %10s\n" $f "skipped"
+	continue
+    fi
+    IN=/dev/null
+    if [ -f "${f%.o}.in" ]; then
+	IN="${f%.o}.in"
+    fi
+    ../target/release/vm $f $ARGS < $IN > "${f%.o}.unopt" 2>/dev/null
+    REF="${f%.o}.unopt"
+    if [ -f "${f%.o}.expected.opt" ]; then
+	REF="${f%.o}.expected.opt"
+    fi
+    OK=1
+    for LEVEL in 1 2;
+    do
+	../target/release/vm $f --opt-level $LEVEL $ARGS < $IN > "${f%.o}.opt" 2>/dev/null
+	if ! diff -q "$REF" "${f%.o}.opt" &>/dev/null; then
+	    printf "%-10s %10s\n" $f "ERROR, --opt-level $LEVEL and unoptimized differ"
+	    ERR=1
+	    OK=0
+	fi
+    done
+    if [ $OK == 1 ]; then
+	printf "%-10s %10s\n" $f "passed"
+    fi
+done
+
+exit $ERR
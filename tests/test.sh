@@ -5,8 +5,32 @@ INPUTS=`ls *.o`
 
 for f in $INPUTS;
 do
-    ../target/release/vm $f > "${f%.o}.student"
+    ARGS=""
+    if [ -f "${f%.o}.args" ]; then
+	ARGS=`cat "${f%.o}.args"`
+    fi
+    # name.X.expected is what the run should leave in name.X: name.err for stderr, or a file it writes
+    EXPECTED=`ls "${f%.o}".*.expected 2>/dev/null`
+    for e in $EXPECTED;
+    do
+	rm -f "${e%.expected}" # written fresh, tracefiles would otherwise gather every run's counts
+    done
+    if [ -f "${f%.o}.in" ]; then
+	../target/release/vm $f $ARGS < "${f%.o}.in" > "${f%.o}.student" 2> "${f%.o}.err"
+    else
+	../target/release/vm $f $ARGS > "${f%.o}.student" 2> "${f%.o}.err"
+    fi
+    OK=1
     if ! diff -q "${f%.o}.student" "${f%.o}.expected" &>/dev/null; then
+	OK=0
+    fi
+    for e in $EXPECTED;
+    do
+	if ! diff -q "${e%.expected}" "$e" &>/dev/null; then
+	    OK=0
+	fi
+    done
+    if [ $OK == 0 ]; then
 	printf "%-10s %10s\n" $f "ERROR, outputs differ"
 	ERR=1
     else
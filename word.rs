@@ -0,0 +1,122 @@
+// Machine values as the stack and heap hold them. A Word is a Copy u64: the upper 32 bits are the
+// tag and the lower 32 bits the payload, so two Words are equal exactly when the Vals they stand
+// for are. Vals are only built at the edges: natives, State::call and the printed result.
+
+use std::fmt;
+
+use super::Val;
+
+pub const TAG_UNIT: u32 = 0;
+pub const TAG_I32: u32 = 1;
+pub const TAG_BOOL: u32 = 2;
+pub const TAG_LOC: u32 = 3;
+pub const TAG_UNDEF: u32 = 4;
+pub const TAG_SIZE: u32 = 5;
+pub const TAG_ADDR: u32 = 6;
+
+#[derive(Clone,Copy,PartialEq,Eq)]
+pub struct Word(u64);
+
+impl Word {
+    pub const UNIT: Word = Word((TAG_UNIT as u64) << 32);
+    pub const UNDEF: Word = Word((TAG_UNDEF as u64) << 32);
+
+    #[inline]
+    fn pack(tag: u32, payload: u32) -> Word {
+        Word(((tag as u64) << 32) | payload as u64)
+    }
+
+    #[inline]
+    pub fn i32(i: i32) -> Word { Word::pack(TAG_I32, i as u32) }
+    #[inline]
+    pub fn bool(b: bool) -> Word { Word::pack(TAG_BOOL, b as u32) }
+    #[inline]
+    pub fn loc(l: u32) -> Word { Word::pack(TAG_LOC, l) }
+    #[inline]
+    pub fn size(n: i32) -> Word { Word::pack(TAG_SIZE, n as u32) }
+    #[inline]
+    pub fn addr(a: usize) -> Word { // the heap is far smaller than 4G values
+        debug_assert!(a <= u32::MAX as usize, "heap address {} does not fit in a Word", a);
+        Word::pack(TAG_ADDR, a as u32)
+    }
+
+    #[inline]
+    pub fn bits(self) -> u64 { // the raw tag and payload, compiled code reads these
+        self.0
+    }
+
+    pub fn from_bits(bits: u64) -> Option<Word> { // function to rebuild a Word from its bits(), None if they are not bits() of some Word
+        let w = Word(bits);
+        let canonical = match w.tag() {
+            TAG_UNIT | TAG_UNDEF => w.payload() == 0,
+            TAG_BOOL => w.payload() <= 1,
+            t => t <= TAG_ADDR
+        };
+        if canonical { Some(w) } else { None }
+    }
+
+    #[inline]
+    pub fn tag(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    #[inline]
+    fn payload(self) -> u32 {
+        self.0 as u32
+    }
+
+    #[inline]
+    fn is(self, tag: u32) -> Option<u32> { // the payload if the Word has this tag
+        if self.tag() == tag { Some(self.payload()) } else { None }
+    }
+
+    // Checked accessors, the interpreter's hot paths use these instead of going through a Val
+    #[inline]
+    pub fn as_i32(self) -> Option<i32> { self.is(TAG_I32).map(|p| p as i32) }
+    #[inline]
+    pub fn as_bool(self) -> Option<bool> { self.is(TAG_BOOL).map(|p| p != 0) }
+    #[inline]
+    pub fn as_loc(self) -> Option<u32> { self.is(TAG_LOC) }
+    #[inline]
+    pub fn as_addr(self) -> Option<usize> { self.is(TAG_ADDR).map(|p| p as usize) }
+
+    #[inline]
+    pub fn val(self) -> Val { // function to turn a Word back into the Val it stands for
+        match self.tag() {
+            TAG_I32 => Val::Vi32(self.payload() as i32),
+            TAG_BOOL => Val::Vbool(self.payload() != 0),
+            TAG_LOC => Val::Vloc(self.payload()),
+            TAG_UNDEF => Val::Vundef,
+            TAG_SIZE => Val::Vsize(self.payload() as i32),
+            TAG_ADDR => Val::Vaddr(self.payload() as usize),
+            _ => Val::Vunit
+        }
+    }
+}
+
+impl<'a> From<&'a Val> for Word {
+    #[inline(always)] // every Push converts its constant, a call here costs more than the conversion
+    fn from(v: &'a Val) -> Word {
+        match *v {
+            Val::Vunit => Word::UNIT,
+            Val::Vi32(i) => Word::i32(i),
+            Val::Vbool(b) => Word::bool(b),
+            Val::Vloc(l) => Word::loc(l),
+            Val::Vundef => Word::UNDEF,
+            Val::Vsize(n) => Word::size(n),
+            Val::Vaddr(a) => Word::addr(a)
+        }
+    }
+}
+
+impl From<Val> for Word {
+    fn from(v: Val) -> Word {
+        Word::from(&v)
+    }
+}
+
+impl fmt::Debug for Word { // prints the same as the Val, so traces and results read the same
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.val())
+    }
+}